@@ -0,0 +1,88 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    thread,
+    time::Duration,
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{KvStore, KvsClient, KvsServer, NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use tempfile::TempDir;
+
+
+// Number of clients writing at the same time and keys each of them writes.
+const CLIENTS: usize = 8;
+const KEYS: usize = 100;
+
+const THREADS: [u32; 4] = [1, 2, 4, 8];
+
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+
+// Start a server on `pool` and wait until it accepts connections. The server
+// runs until the benchmark exits.
+fn start_server<P: ThreadPool + Send + Sync + 'static>(dir: &TempDir, pool: P) -> SocketAddr {
+    let addr = free_addr();
+    let server = KvsServer::new(KvStore::open(dir.path()).unwrap()).pool(pool);
+    thread::spawn(move || server.run(addr));
+    while KvsClient::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    addr
+}
+
+
+// Every client connects, writes its keys and reads them back.
+fn clients(addr: SocketAddr) {
+    let handles: Vec<_> = (0..CLIENTS).map(|client| thread::spawn(move || {
+        let mut conn = KvsClient::connect(addr).unwrap();
+        for i in 0..KEYS {
+            let key = format!("client{}-key{}", client, i);
+            conn.set(key.clone(), "value".to_owned()).unwrap();
+            assert!(conn.get(key).unwrap().is_some());
+        }
+    })).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+
+// The same writes and reads on the store directly, without a server.
+fn store(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(dir.path()).unwrap();
+    c.bench_function("store", |b| b.iter(|| {
+        for client in 0..CLIENTS {
+            for i in 0..KEYS {
+                let key = format!("client{}-key{}", client, i);
+                store.set(key.clone(), "value".to_owned()).unwrap();
+                assert!(store.get(key).unwrap().is_some());
+            }
+        }
+    }));
+}
+
+
+fn pools(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pool");
+    group.sample_size(20);
+    let mut dirs = Vec::new();
+    dirs.push(TempDir::new().unwrap());
+    let addr = start_server(dirs.last().unwrap(), NaiveThreadPool::new(0).unwrap());
+    group.bench_function("naive", |b| b.iter(|| clients(addr)));
+    for threads in THREADS.iter() {
+        dirs.push(TempDir::new().unwrap());
+        let addr = start_server(dirs.last().unwrap(), SharedQueueThreadPool::new(*threads).unwrap());
+        group.bench_with_input(BenchmarkId::new("shared-queue", threads), &addr, |b, addr| b.iter(|| clients(*addr)));
+        dirs.push(TempDir::new().unwrap());
+        let addr = start_server(dirs.last().unwrap(), RayonThreadPool::new(*threads).unwrap());
+        group.bench_with_input(BenchmarkId::new("rayon", threads), &addr, |b, addr| b.iter(|| clients(*addr)));
+    }
+    group.finish();
+}
+
+
+criterion_group!(benches, store, pools);
+criterion_main!(benches);
@@ -0,0 +1,101 @@
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, ToSocketAddrs,
+    },
+    time,
+};
+
+use crate::client::{unexpected, ELECTION_WAIT, MAX_REDIRECTS};
+use crate::error::*;
+use crate::protocol::{self, Request, Response};
+
+
+// The async counterpart of `KvsClient`; the methods behave like the ones of
+// the same name there.
+pub struct AsyncKvsClient {
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+    buf: Vec<u8>,
+}
+
+
+impl AsyncKvsClient {
+
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<AsyncKvsClient> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        Ok(AsyncKvsClient { reader, writer, buf: Vec::new() })
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(&Request::Get { key }).await? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.request(&Request::Set { key, value }).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(&Request::Remove { key }).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.request(&Request::GetMany { keys }).await? {
+            Response::Values(values) => Ok(values),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self.request(&Request::SetMany { pairs }).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn remove_many(&mut self, keys: Vec<String>) -> Result<usize> {
+        match self.request(&Request::RemoveMany { keys }).await? {
+            Response::Count(count) => Ok(count),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn scan(&mut self, from: Option<String>, to: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        match self.request(&Request::Scan { from, to, limit }).await? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn request(&mut self, request: &Request) -> Result<Response> {
+        for _ in 0..MAX_REDIRECTS {
+            self.writer.write_all(&serde_json::to_vec(request)?).await?;
+            match self.receive().await? {
+                Response::NotLeader(Some(addr)) => *self = AsyncKvsClient::connect(addr).await?,
+                Response::NotLeader(None) => time::sleep(ELECTION_WAIT).await,
+                response => return Ok(response),
+            }
+        }
+        Err(KvsError::NoLeader)
+    }
+
+    async fn receive(&mut self) -> Result<Response> {
+        match protocol::read_message(&mut self.reader, &mut self.buf).await? {
+            Some(Response::KeyNotFound) => Err(KvsError::KeyNotFound),
+            Some(Response::Err(msg)) => Err(KvsError::Remote(msg)),
+            Some(response) => Ok(response),
+            None => Err(KvsError::Protocol("the server closed the connection".to_owned())),
+        }
+    }
+
+}
@@ -0,0 +1,86 @@
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use crate::async_store::AsyncKvStore;
+use crate::error::*;
+use crate::protocol::{self, Request, Response};
+
+
+// Serves an `AsyncKvStore` over TCP with the same protocol as `KvsServer`,
+// every connection is a task on the runtime. It is a standalone primary:
+// replication, cluster and snapshot requests are answered with an error.
+pub struct AsyncKvsServer {
+    store: AsyncKvStore,
+}
+
+
+impl AsyncKvsServer {
+
+    pub fn new(store: AsyncKvStore) -> AsyncKvsServer {
+        AsyncKvsServer { store }
+    }
+
+    // Accept connections on `addr` until the runtime shuts down.
+    pub async fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    eprintln!("failed to accept a connection: {}", err);
+                    continue;
+                },
+            };
+            let store = self.store.clone();
+            tokio::spawn(async move {
+                if let Err(err) = serve(store, stream).await {
+                    eprintln!("connection failed: {}", err);
+                }
+            });
+        }
+    }
+
+}
+
+
+async fn serve(store: AsyncKvStore, stream: TcpStream) -> Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buf = Vec::new();
+    while let Some(request) = protocol::read_message(&mut reader, &mut buf).await? {
+        let response = match request {
+            Request::Tagged { id, request } => {
+                let response = match *request {
+                    Request::Tagged { .. } => Response::Err("a tagged request cannot be tagged".to_owned()),
+                    request => handle(&store, request).await,
+                };
+                Response::Tagged { id, response: Box::new(response) }
+            },
+            request => handle(&store, request).await,
+        };
+        writer.write_all(&serde_json::to_vec(&response)?).await?;
+    }
+    Ok(())
+}
+
+
+async fn handle(store: &AsyncKvStore, request: Request) -> Response {
+    let result = match request {
+        Request::Get { key } => store.get(key).await.map(Response::Value),
+        Request::Set { key, value } => store.set(key, value).await.map(|_| Response::Ok),
+        Request::Remove { key } => store.remove(key).await.map(|_| Response::Ok),
+        Request::GetMany { keys } => store.get_many(keys).await.map(Response::Values),
+        Request::SetMany { pairs } => store.set_many(pairs).await.map(|_| Response::Ok),
+        Request::RemoveMany { keys } => store.remove_many(keys).await.map(Response::Count),
+        Request::Scan { from, to, limit } => store.scan(from, to, limit).await.map(Response::Pairs),
+        Request::Fetch { .. } | Request::Snapshot => {
+            Ok(Response::Err("the async server does not support replication".to_owned()))
+        },
+        Request::Raft(_) | Request::AddNode { .. } | Request::RemoveNode { .. } | Request::Status => {
+            Ok(Response::Err("the server is not a cluster node".to_owned()))
+        },
+        Request::Tagged { .. } => Ok(Response::Err("unexpected tagged request".to_owned())),
+    };
+    result.unwrap_or_else(Response::from)
+}
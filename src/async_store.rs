@@ -0,0 +1,91 @@
+use std::{
+    self,
+    ops::Bound,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::task;
+
+use crate::batch::WriteBatch;
+use crate::error::*;
+use crate::KvStore;
+
+
+// A `KvStore` for async code. Every operation runs on tokio's blocking pool,
+// so the file I/O never stalls the runtime. Clones share the same store and
+// their operations run one at a time.
+#[derive(Clone)]
+pub struct AsyncKvStore {
+    store: Arc<Mutex<KvStore>>,
+}
+
+
+impl AsyncKvStore {
+
+    pub fn new(store: KvStore) -> AsyncKvStore {
+        AsyncKvStore { store: Arc::new(Mutex::new(store)) }
+    }
+
+    pub async fn open<P: AsRef<Path>>(dirname: P) -> Result<AsyncKvStore> {
+        let dirname = dirname.as_ref().to_owned();
+        let store = task::spawn_blocking(move || KvStore::open(dirname)).await.map_err(|_| KvsError::Poisoned)??;
+        Ok(AsyncKvStore::new(store))
+    }
+
+    // Run `f` on the store on the blocking pool. A panic in `f` poisons the
+    // store like it does for a thread.
+    async fn with<T, F>(&self, f: F) -> Result<T>
+        where T: Send + 'static, F: FnOnce(&mut KvStore) -> Result<T> + Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        task::spawn_blocking(move || f(&mut *store.lock().map_err(|_| KvsError::Poisoned)?))
+            .await
+            .map_err(|_| KvsError::Poisoned)?
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.with(move |store| store.get(key)).await
+    }
+
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.with(move |store| store.set(key, value)).await
+    }
+
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.with(move |store| store.remove(key)).await
+    }
+
+    pub async fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.with(move |store| store.write(batch)).await
+    }
+
+    pub async fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.with(move |store| store.set_many(pairs)).await
+    }
+
+    pub async fn remove_many(&self, keys: Vec<String>) -> Result<usize> {
+        self.with(move |store| store.remove_many(keys)).await
+    }
+
+    pub async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.with(move |store| keys.into_iter().map(|key| store.get(key)).collect()).await
+    }
+
+    // At most `limit` pairs with `from <= key < to` in key order, an open
+    // bound when None.
+    pub async fn scan(&self, from: Option<String>, to: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.with(move |store| {
+            let range = (from.map_or(Bound::Unbounded, Bound::Included), to.map_or(Bound::Unbounded, Bound::Excluded));
+            store.scan(range)?.take(limit).collect()
+        }).await
+    }
+
+    pub async fn keys(&self) -> Result<Vec<String>> {
+        self.with(|store| store.keys()).await
+    }
+
+    pub async fn compact(&self) -> Result<()> {
+        self.with(|store| store.compact()).await
+    }
+
+}
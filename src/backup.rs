@@ -0,0 +1,69 @@
+use std::{
+    self,
+    io::{self, Read},
+    fs::{self, File},
+    path::Path,
+};
+
+use crate::error::*;
+use crate::lock::{DirLock, LockMode};
+use crate::log::{Log, LogPartition};
+use crate::options::KvStoreOptions;
+
+
+fn prepare_target(target: &Path) -> Result<()> {
+    fs::create_dir_all(target)?;
+    if Log::exists(target) {
+        return Err(KvsError::StoreExists);
+    }
+    Ok(())
+}
+
+
+fn copy_partition(partition: &LogPartition, from: &Path, to: &Path, len: Option<u64>) -> Result<()> {
+    let src = partition.full_path(from);
+    let mut reader: Box<dyn Read> = match len {
+        Some(len) => Box::new(File::open(&src)?.take(len)),
+        None => Box::new(File::open(&src)?),
+    };
+    let mut writer = File::create(partition.full_path(to))?;
+    io::copy(&mut reader, &mut writer)?;
+    writer.sync_all()?;
+    Ok(())
+}
+
+
+// Write a consistent copy of the log to `target`. Sealed partitions are never
+// written to again, a repair replaces them with a new file, so they are
+// hard-linked when the file system allows it. The active partition is copied
+// up to its current end. The caller holds the store, so nothing can append to
+// it or compact it while this runs.
+pub(crate) fn backup_log(log: &Log, target: &Path) -> Result<()> {
+    prepare_target(target)?;
+    for partition in &log.hist {
+        let linked = fs::hard_link(partition.full_path(&log.dirname), partition.full_path(target));
+        if linked.is_err() {
+            copy_partition(partition, &log.dirname, target, None)?;
+        }
+    }
+    let len = fs::metadata(log.active.full_path(&log.dirname))?.len();
+    copy_partition(&log.active, &log.dirname, target, Some(len))?;
+    log.copy_meta(target).dump_meta()
+}
+
+
+// Restore the backup in `backup` into the directory `target`, which must not
+// contain a store yet. All partitions are copied so the backup stays intact.
+pub fn restore(backup: &Path, target: &Path) -> Result<()> {
+    let _backup_lock = DirLock::acquire(backup, LockMode::Shared)?;
+    let log = Log::open_read_only(backup, &KvStoreOptions::default())?;
+    // lock the target before checking it is empty, so no store is created
+    // there in between
+    fs::create_dir_all(target)?;
+    let _lock = DirLock::acquire(target, LockMode::Exclusive)?;
+    prepare_target(target)?;
+    for partition in log.hist.iter().chain(Some(&log.active)) {
+        copy_partition(partition, backup, target, None)?;
+    }
+    log.copy_meta(target).dump_meta()
+}
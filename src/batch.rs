@@ -0,0 +1,39 @@
+use crate::log::Entry;
+
+
+// A group of writes appended to the log together, see `KvStore::write`.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) entries: Vec<Entry<String, String>>,
+}
+
+
+impl WriteBatch {
+
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> &mut WriteBatch {
+        self.entries.push(Entry::Set(key, value));
+        self
+    }
+
+    pub fn remove(&mut self, key: String) -> &mut WriteBatch {
+        self.entries.push(Entry::Remove(key));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+}
@@ -0,0 +1,150 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+};
+use structopt::StructOpt;
+use kvs::{KvsClient, KvsError, Result};
+
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+
+// Where the server is, shared by all commands.
+#[derive(StructOpt, Debug)]
+struct Server {
+    /// The address of the server
+    #[structopt(long, default_value = DEFAULT_ADDR)]
+    addr: SocketAddr,
+    /// Connect to the Unix domain socket at PATH instead of --addr
+    #[cfg(unix)]
+    #[structopt(long, value_name = "PATH", parse(from_os_str))]
+    socket: Option<PathBuf>,
+}
+
+
+impl Server {
+
+    fn connect(&self) -> Result<KvsClient> {
+        #[cfg(unix)]
+        {
+            if let Some(ref path) = self.socket {
+                return KvsClient::connect_unix(path);
+            }
+        }
+        KvsClient::connect(self.addr)
+    }
+
+}
+
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Get the VALUE associated with KEY
+    Get {
+        key: String,
+        #[structopt(flatten)]
+        server: Server,
+    },
+    /// Set a KEY with associated VALUE
+    Set {
+        key: String,
+        value: String,
+        #[structopt(flatten)]
+        server: Server,
+    },
+    /// Remove KEY
+    Rm {
+        key: String,
+        #[structopt(flatten)]
+        server: Server,
+    },
+    /// Get the values of all KEYS, one per line
+    Mget {
+        #[structopt(required = true)]
+        keys: Vec<String>,
+        #[structopt(flatten)]
+        server: Server,
+    },
+    /// Set all KEY VALUE pairs in one batch
+    Mset {
+        #[structopt(value_name = "KEY VALUE", required = true)]
+        pairs: Vec<String>,
+        #[structopt(flatten)]
+        server: Server,
+    },
+    /// Remove the KEYS that exist in one batch and print how many there were
+    Mdel {
+        #[structopt(required = true)]
+        keys: Vec<String>,
+        #[structopt(flatten)]
+        server: Server,
+    },
+    /// Show the Raft state of a cluster node
+    Status {
+        #[structopt(flatten)]
+        server: Server,
+    },
+    /// Add node ID serving at NODE_ADDR to the cluster
+    AddNode {
+        id: u64,
+        node_addr: SocketAddr,
+        #[structopt(flatten)]
+        server: Server,
+    },
+    /// Remove node ID from the cluster
+    RemoveNode {
+        id: u64,
+        #[structopt(flatten)]
+        server: Server,
+    },
+}
+
+
+fn run(cmd: Command) -> Result<()> {
+    match cmd {
+        Command::Get { key, server } => {
+            match server.connect()?.get(key)? {
+                Some(value) => println!("{}", value),
+                None => println!("Key not found"),
+            }
+        },
+        Command::Set { key, value, server } => server.connect()?.set(key, value)?,
+        Command::Rm { key, server } => server.connect()?.remove(key)?,
+        Command::Mget { keys, server } => {
+            for value in server.connect()?.get_many(keys)? {
+                println!("{}", value.as_deref().unwrap_or("Key not found"));
+            }
+        },
+        Command::Mset { pairs, server } => {
+            if pairs.len() % 2 != 0 {
+                return Err(KvsError::InvalidOptions("mset takes KEY VALUE pairs".to_owned()));
+            }
+            let pairs = pairs.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+            server.connect()?.set_many(pairs)?;
+        },
+        Command::Mdel { keys, server } => println!("{}", server.connect()?.remove_many(keys)?),
+        Command::Status { server } => {
+            let status = server.connect()?.status()?;
+            let members: Vec<_> = status.members.iter().map(|m| format!("{}={}", m.id, m.addr)).collect();
+            println!("node {}: {} in term {}", status.id, status.role, status.term);
+            match status.leader {
+                Some(leader) => println!("leader: {}", leader),
+                None => println!("leader: none"),
+            }
+            println!("commit: {}, applied: {}", status.commit, status.applied);
+            println!("members: {}", members.join(","));
+        },
+        Command::AddNode { id, node_addr, server } => server.connect()?.add_node(id, node_addr)?,
+        Command::RemoveNode { id, server } => server.connect()?.remove_node(id)?,
+    }
+    Ok(())
+}
+
+
+fn main() {
+    if let Err(err) = run(Command::from_args()) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    env,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+    thread,
+};
+use structopt::StructOpt;
+use kvs::{KvStore, KvsServer, Member, NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool};
+
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    /// The address to listen on
+    #[structopt(long, default_value = DEFAULT_ADDR)]
+    addr: SocketAddr,
+    /// Listen on a Unix domain socket at PATH instead of --addr
+    #[cfg(unix)]
+    #[structopt(long, value_name = "PATH", parse(from_os_str), conflicts_with = "node-id")]
+    socket: Option<PathBuf>,
+    /// The permissions of the socket in octal, only users allowed to write to
+    /// it can connect
+    #[cfg(unix)]
+    #[structopt(long, value_name = "MODE", default_value = "600", parse(try_from_str = parse_mode))]
+    socket_mode: u32,
+    /// The directory of the store, defaults to the current directory
+    #[structopt(short, long, parse(from_os_str))]
+    path: Option<PathBuf>,
+    /// Replicate the primary at ADDR and serve reads only
+    #[structopt(long, value_name = "ADDR", conflicts_with = "node-id")]
+    follow: Option<SocketAddr>,
+    /// Also serve Redis clients on ADDR
+    #[structopt(long, value_name = "ADDR", conflicts_with = "node-id")]
+    resp: Option<SocketAddr>,
+    /// Also serve the REST interface on ADDR
+    #[cfg(feature = "http")]
+    #[structopt(long, value_name = "ADDR", conflicts_with = "node-id")]
+    http: Option<SocketAddr>,
+    /// The thread pool serving connections, naive starts a thread for each
+    #[structopt(long, default_value = "naive", possible_values = &["naive", "shared-queue", "rayon"])]
+    pool: String,
+    /// The number of threads of a shared-queue or rayon pool, defaults to the
+    /// number of CPUs; it limits the connections served at once
+    #[structopt(long, value_name = "N")]
+    threads: Option<u32>,
+    /// Run as node ID of a Raft cluster
+    #[structopt(long, value_name = "ID")]
+    node_id: Option<u64>,
+    /// The initial members of a new cluster, as ID=ADDR pairs; a node started
+    /// without them waits to be added with `kvs-client add-node`
+    #[structopt(long, value_name = "ID=ADDR", requires = "node-id", use_delimiter = true, parse(try_from_str = parse_member))]
+    cluster: Vec<Member>,
+}
+
+
+fn parse_member(s: &str) -> std::result::Result<Member, String> {
+    let mut parts = s.splitn(2, '=');
+    let id = parts.next().unwrap_or("").parse().map_err(|_| format!("invalid node id in {}", s))?;
+    let addr = parts.next().ok_or(format!("expected ID=ADDR, found {}", s))?;
+    let addr = addr.parse().map_err(|_| format!("invalid address in {}", s))?;
+    Ok(Member { id, addr })
+}
+
+
+fn parse_mode(s: &str) -> std::result::Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("invalid mode {}, expected octal permissions like 660", s)),
+    }
+}
+
+
+fn run(opt: Opt) -> Result<()> {
+    let dirname = opt.path.unwrap_or(env::current_dir()?);
+    let store = KvStore::open(&dirname)?;
+    let mut server = KvsServer::new(store);
+    let threads = match opt.threads {
+        Some(threads) => threads,
+        None => thread::available_parallelism().map_or(1, |n| n.get() as u32),
+    };
+    server = match opt.pool.as_str() {
+        "shared-queue" => server.pool(SharedQueueThreadPool::new(threads)?),
+        "rayon" => server.pool(RayonThreadPool::new(threads)?),
+        _ => server.pool(NaiveThreadPool::new(threads)?),
+    };
+    if let Some(primary) = opt.follow {
+        server = server.follow(primary)?;
+    }
+    if let Some(id) = opt.node_id {
+        server = server.cluster(id, opt.cluster);
+    }
+    if let Some(resp_addr) = opt.resp {
+        server = server.resp(resp_addr)?;
+    }
+    #[cfg(feature = "http")]
+    {
+        if let Some(http_addr) = opt.http {
+            server = server.http(http_addr)?;
+        }
+    }
+    #[cfg(unix)]
+    {
+        server = server.socket_mode(opt.socket_mode);
+    }
+    let listening = opt.addr.to_string();
+    #[cfg(unix)]
+    let listening = opt.socket.as_ref().map_or(listening, |path| path.display().to_string());
+    eprintln!("kvs-server {} listening on {}", env!("CARGO_PKG_VERSION"), listening);
+    match opt.pool.as_str() {
+        "naive" => eprintln!("serving connections on a thread each"),
+        pool => eprintln!("serving connections on a {} pool of {} threads", pool, threads),
+    }
+    if let Some(primary) = opt.follow {
+        eprintln!("following {}", primary);
+    }
+    if let Some(id) = opt.node_id {
+        eprintln!("running as cluster node {}", id);
+    }
+    if let Some(resp_addr) = opt.resp {
+        eprintln!("serving RESP on {}", resp_addr);
+    }
+    #[cfg(feature = "http")]
+    {
+        if let Some(http_addr) = opt.http {
+            eprintln!("serving HTTP on {}", http_addr);
+        }
+    }
+    // sync the store on SIGINT, SIGTERM and SIGHUP before exiting
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || {
+        if let Err(err) = shutdown.shutdown() {
+            eprintln!("error: failed to sync the store: {}", err);
+            process::exit(1);
+        }
+        process::exit(0);
+    }).map_err(io::Error::other)?;
+    #[cfg(unix)]
+    {
+        if let Some(path) = opt.socket {
+            return server.run_unix(path);
+        }
+    }
+    server.run(opt.addr)
+}
+
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(err) = run(opt) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
@@ -1,15 +1,43 @@
 use std::{
     env,
-    path::PathBuf,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
 };
 use structopt::StructOpt;
-use kvs::{KvStore, Result};
+use rustyline::{error::ReadlineError, Editor};
+use serde_json::json;
+use kvs::{backup, format, glob_match, verify, KvStore, KvsError, Result};
+use kvs::transfer::{self, Conflict, Format};
+
+
+const EXIT_ERROR: i32 = 1;
+const EXIT_NOT_FOUND: i32 = 2;
+const EXIT_CORRUPTION: i32 = 3;
+const EXIT_LOCKED: i32 = 4;
+const EXIT_IO: i32 = 5;
+
+const EXIT_CODES_HELP: &str = "\
+EXIT CODES:
+    0    success, also for get of a missing key
+    1    invalid arguments or any other error
+    2    the key to remove does not exist
+    3    the store is corrupt or has an unsupported format version
+    4    the store is locked by another process
+    5    reading or writing a file failed";
 
 
 #[derive(StructOpt, Debug)]
+#[structopt(after_help = EXIT_CODES_HELP)]
 struct Kvs {
     #[structopt(short, long, parse(from_os_str))]
     path: Option<PathBuf>,
+    /// text or json, json prints results and errors as JSON
+    #[structopt(long, default_value = "text")]
+    output: Output,
     #[structopt(subcommand)]
     cmd: Command,
 }
@@ -25,57 +53,572 @@ enum Command {
     /// Set a KEY with associated VALUE
     Set {
         key: String,
-        value: String,
+        #[structopt(required_unless = "value-file")]
+        value: Option<String>,
+        /// Read the value from FILE instead, - reads stdin
+        #[structopt(long, parse(from_os_str), conflicts_with = "value")]
+        value_file: Option<PathBuf>,
     },
     /// Remove KEY
     Rm {
         key: String
     },
+    /// Write a consistent copy of the store to DIR. The store must not be open
+    /// for writing, e.g. by kvs-server; a program holding the store can back
+    /// it up with KvStore::backup_to
+    Backup {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+    /// Restore the backup in DIR into the store path, which must not hold a store yet
+    Restore {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+    /// Write all key/value pairs to FILE or stdout
+    Export {
+        /// jsonl or csv
+        #[structopt(long, default_value = "jsonl")]
+        format: Format,
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+    /// Read key/value pairs from FILE or stdin into the store
+    Import {
+        /// jsonl or csv
+        #[structopt(long, default_value = "jsonl")]
+        format: Format,
+        /// Overwrite existing keys (the default)
+        #[structopt(long)]
+        replace: bool,
+        /// Keep the value of existing keys
+        #[structopt(long, conflicts_with = "replace")]
+        skip_existing: bool,
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+    /// List the keys, optionally only those matching a glob PATTERN
+    Keys {
+        /// Only list keys starting with PREFIX
+        #[structopt(long)]
+        prefix: Option<String>,
+        pattern: Option<String>,
+        /// tsv or json, defaults to json with --output json
+        #[structopt(long)]
+        format: Option<Listing>,
+    },
+    /// Print the key/value pairs with FROM <= key < TO in key order
+    Scan {
+        #[structopt(long)]
+        from: Option<String>,
+        #[structopt(long)]
+        to: Option<String>,
+        #[structopt(long)]
+        limit: Option<usize>,
+        /// tsv or json, defaults to json with --output json
+        #[structopt(long)]
+        format: Option<Listing>,
+    },
+    /// Print the number of keys
+    Count {
+        /// Only count keys starting with PREFIX
+        #[structopt(long)]
+        prefix: Option<String>,
+    },
+    /// Show statistics about the store
+    Stats {
+        /// Print the statistics as JSON, the same as --output json
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Open the store once and read commands interactively
+    Shell {
+        /// Read commands from stdin without a prompt, stopping at the first error
+        #[structopt(long)]
+        batch: bool,
+    },
+    /// Rewrite a store written by an older version into the current format
+    Migrate,
+    /// Check the integrity of the store
+    Verify {
+        /// Move corrupt or orphaned data into the quarantine directory and fix logparts
+        #[structopt(long)]
+        repair: bool,
+    },
 }
 
 
-fn run() -> Result<()> {
-    let opts = Kvs::from_args();
+impl Command {
+
+    // Commands that only read take a shared lock, so any number of them can
+    // run at the same time.
+    fn is_read_only(&self) -> bool {
+        matches!(*self,
+            Command::Get { .. }
+            | Command::Keys { .. }
+            | Command::Scan { .. }
+            | Command::Count { .. }
+            | Command::Stats { .. }
+            | Command::Export { .. }
+            | Command::Backup { .. })
+    }
+
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    Text,
+    Json,
+}
+
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Output, String> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => Err(format!("unknown output {}, expected text or json", s)),
+        }
+    }
+}
+
+
+impl Output {
+
+    fn listing(self, format: Option<Listing>) -> Listing {
+        match (format, self) {
+            (Some(format), _) => format,
+            (None, Output::Text) => Listing::Tsv,
+            (None, Output::Json) => Listing::Json,
+        }
+    }
+
+    fn value(self, key: &str, value: Option<&str>) {
+        match (self, value) {
+            (Output::Text, Some(value)) => println!("{}", value),
+            (Output::Text, None) => println!("Key not found"),
+            (Output::Json, value) => println!("{}", json!({ "key": key, "value": value })),
+        }
+    }
+
+    // Commands without a result only report success in json.
+    fn done(self) {
+        if self == Output::Json {
+            println!("{}", json!({ "ok": true }));
+        }
+    }
+
+    fn error(self, err: &KvsError) {
+        match self {
+            Output::Text => eprintln!("error: {}", err),
+            Output::Json => eprintln!("{}", json!({ "error": err.to_string(), "exit_code": exit_code(err) })),
+        }
+    }
+
+}
+
+
+fn exit_code(err: &KvsError) -> i32 {
+    if err.is_corruption() || matches!(err, KvsError::UnsupportedFormatVersion { .. }) {
+        return EXIT_CORRUPTION;
+    }
+    match *err {
+        KvsError::KeyNotFound => EXIT_NOT_FOUND,
+        KvsError::Locked { .. } => EXIT_LOCKED,
+        KvsError::Io(_) => EXIT_IO,
+        _ => EXIT_ERROR,
+    }
+}
+
+
+// Output format for listings. Tab separated values escape tabs, newlines and
+// backslashes, json prints one JSON value per line.
+#[derive(Debug, Clone, Copy)]
+enum Listing {
+    Tsv,
+    Json,
+}
+
+
+impl FromStr for Listing {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Listing, String> {
+        match s {
+            "tsv" => Ok(Listing::Tsv),
+            "json" => Ok(Listing::Json),
+            _ => Err(format!("unknown format {}, expected tsv or json", s)),
+        }
+    }
+}
+
+
+fn tsv_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+
+fn write_key<W: Write>(out: &mut W, format: Listing, key: &str) -> Result<()> {
+    match format {
+        Listing::Tsv => writeln!(out, "{}", tsv_escape(key))?,
+        Listing::Json => writeln!(out, "{}", serde_json::to_string(key)?)?,
+    }
+    Ok(())
+}
+
+
+fn write_pair<W: Write>(out: &mut W, format: Listing, key: &str, value: &str) -> Result<()> {
+    match format {
+        Listing::Tsv => writeln!(out, "{}\t{}", tsv_escape(key), tsv_escape(value))?,
+        Listing::Json => writeln!(out, "{}", json!({ "key": key, "value": value }))?,
+    }
+    Ok(())
+}
+
+
+// Values are strings, so files and stdin have to hold UTF-8.
+fn read_value(path: &Path) -> Result<String> {
+    let mut buf = Vec::new();
+    match path.to_str() {
+        Some("-") => io::stdin().lock().read_to_end(&mut buf)?,
+        _ => File::open(path)?.read_to_end(&mut buf)?,
+    };
+    String::from_utf8(buf).map_err(|_| KvsError::InvalidInput("the value is not valid UTF-8".to_owned()))
+}
+
+
+// ~~~~~ Shell ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+const SHELL_HELP: &str = "\
+get KEY              print the value of KEY
+set KEY VALUE        set KEY to VALUE
+rm KEY               remove KEY
+scan [FROM [TO]]     print the pairs with FROM <= key < TO in key order
+keys [PATTERN]       list the keys matching the glob PATTERN
+stats                show statistics about the store
+compact              compact the log now
+help                 show this help
+exit                 leave the shell
+Arguments with spaces are quoted with \" or ', \\ escapes the next character.";
+
+
+// Split a shell line into words. Quotes group words with spaces and a
+// backslash escapes the next character outside single quotes.
+fn split_words(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (_, '\\') => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("trailing backslash".to_owned()),
+            },
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            },
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("unterminated quote".to_owned());
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+
+// Run a single shell line. Returns false when the shell should stop.
+fn shell_command(store: &mut KvStore, output: Output, line: &str) -> Result<bool> {
+    let words = split_words(line).map_err(KvsError::InvalidInput)?;
+    let words: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+    let mut out = io::stdout().lock();
+    match words[..] {
+        [] => {},
+        ["get", key] => output.value(key, store.get(key.to_owned())?.as_deref()),
+        ["set", key, value] => {
+            store.set(key.to_owned(), value.to_owned())?;
+            output.done();
+        },
+        ["rm", key] => {
+            store.remove(key.to_owned())?;
+            output.done();
+        },
+        ["scan", ref range @ ..] if range.len() <= 2 => {
+            let from = range.first().map_or(Bound::Unbounded, |k| Bound::Included(k.to_string()));
+            let to = range.get(1).map_or(Bound::Unbounded, |k| Bound::Excluded(k.to_string()));
+            for pair in store.scan((from, to))? {
+                let (key, value) = pair?;
+                write_pair(&mut out, output.listing(None), &key, &value)?;
+            }
+        },
+        ["keys"] => {
+            for key in store.keys()? {
+                write_key(&mut out, output.listing(None), &key)?;
+            }
+        },
+        ["keys", pattern] => {
+            for key in store.keys()?.iter().filter(|key| glob_match(pattern, key)) {
+                write_key(&mut out, output.listing(None), key)?;
+            }
+        },
+        ["stats"] => print_stats(store, output)?,
+        ["compact"] => {
+            store.compact()?;
+            output.done();
+        },
+        ["help"] => println!("{}", SHELL_HELP),
+        ["exit"] | ["quit"] => return Ok(false),
+        _ => return Err(KvsError::InvalidInput(format!("cannot parse `{}`, try help", line.trim()))),
+    }
+    Ok(true)
+}
+
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvs_history"))
+}
+
+
+fn shell(store: &mut KvStore, output: Output) -> Result<()> {
+    let mut editor = Editor::<()>::new();
+    let history = history_path();
+    if let Some(ref path) = history {
+        // there is no history on the first run
+        let _ = editor.load_history(path);
+    }
+    loop {
+        let line = match editor.readline("kvs> ") {
+            Ok(line) => line,
+            // ctrl-c discards the line, ctrl-d leaves the shell
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(ReadlineError::Io(err)) => return Err(KvsError::from(err)),
+            Err(err) => return Err(KvsError::from(io::Error::other(err.to_string()))),
+        };
+        editor.add_history_entry(line.as_str());
+        match shell_command(store, output, &line) {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(err) => output.error(&err),
+        }
+    }
+    if let Some(ref path) = history {
+        if let Err(err) = editor.save_history(path) {
+            eprintln!("could not save history: {}", err);
+        }
+    }
+    Ok(())
+}
+
+
+// Commands read from stdin, one per line. Lines starting with # are skipped.
+fn shell_batch(store: &mut KvStore, output: Output) -> Result<()> {
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        if !shell_command(store, output, &line)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+
+fn print_stats(store: &KvStore, output: Output) -> Result<()> {
+    let stats = store.stats()?;
+    match output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&stats)?),
+        Output::Text => println!("{}", stats),
+    }
+    Ok(())
+}
+
+
+fn run(opts: Kvs) -> Result<()> {
+    let output = opts.output;
     let dirname = opts.path.unwrap_or(env::current_dir()?);
-    let mut store = KvStore::open(dirname)?;
-    let matches = Kvs::clap().get_matches();
-    match matches.subcommand() {
-        ("get", Some(m)) => {
-            // values must be Some(_) else clap would have failed
-            let key = m.value_of("key").unwrap();
-            eprintln!("calling store.get({})", key);
-            match store.get(key.to_owned())? {
-                Some(v) => println!("{}", &v),
-                None => println!("Key not found"),
+    // verify works on the directory, the store may be too damaged to open
+    if let Command::Verify { repair } = opts.cmd {
+        let report = verify::verify_dir(&dirname, repair)?;
+        let problems: Vec<String> = report.problems.iter().map(|p| p.to_string()).collect();
+        match output {
+            Output::Json => {
+                println!("{}", json!({
+                    "partitions": report.partitions,
+                    "records": report.records,
+                    "problems": problems,
+                    "repaired": report.repaired,
+                }));
+            },
+            Output::Text => {
+                for problem in &problems {
+                    println!("{}", problem);
+                }
+                println!("{} partitions, {} records, {} problems{}",
+                         report.partitions, report.records, report.problems.len(),
+                         if report.repaired { " (repaired)" } else { "" });
+            },
+        }
+        if !report.is_ok() && !report.repaired {
+            process::exit(EXIT_CORRUPTION);
+        }
+        return Ok(());
+    }
+    if let Command::Migrate = opts.cmd {
+        let from = format::migrate(&dirname)?;
+        match output {
+            Output::Json => println!("{}", json!({ "from": from, "to": format::FORMAT_VERSION })),
+            Output::Text if from == format::FORMAT_VERSION => println!("already at format version {}", from),
+            Output::Text => println!("migrated from format version {} to {}", from, format::FORMAT_VERSION),
+        }
+        return Ok(());
+    }
+    if let Command::Restore { dir } = opts.cmd {
+        backup::restore(&dir, &dirname)?;
+        output.done();
+        return Ok(());
+    }
+    let mut store = match opts.cmd.is_read_only() {
+        // a directory without a store still reads as an empty one
+        true => match KvStore::open_read_only(&dirname) {
+            Err(KvsError::StoreNotFound) => KvStore::open(&dirname)?,
+            result => result?,
+        },
+        false => KvStore::open(&dirname)?,
+    };
+    run_command(&mut store, opts.cmd, output)?;
+    store.close()
+}
+
+
+fn run_command(store: &mut KvStore, cmd: Command, output: Output) -> Result<()> {
+    match cmd {
+        Command::Get { key } => {
+            let value = store.get(key.clone())?;
+            output.value(&key, value.as_deref());
+            Ok(())
+        }
+        Command::Set { key, value, value_file } => {
+            // clap requires one of value and --value-file
+            let value = match value_file {
+                Some(path) => read_value(&path)?,
+                None => value.unwrap_or_default(),
+            };
+            store.set(key, value)?;
+            output.done();
+            Ok(())
+        }
+        Command::Rm { key } => {
+            store.remove(key)?;
+            output.done();
+            Ok(())
+        }
+        Command::Export { format, file } => {
+            match file {
+                Some(path) => {
+                    let count = transfer::export(store, BufWriter::new(File::create(path)?), format)?;
+                    if output == Output::Json {
+                        println!("{}", json!({ "exported": count }));
+                    }
+                },
+                None => {
+                    transfer::export(store, BufWriter::new(io::stdout().lock()), format)?;
+                },
+            };
+            Ok(())
+        }
+        Command::Import { format, replace, skip_existing, file } => {
+            // clap rejects passing both flags
+            let conflict = match (replace, skip_existing) {
+                (false, true) => Conflict::SkipExisting,
+                _ => Conflict::Replace,
+            };
+            let progress = |stats: &transfer::ImportStats| {
+                if output == Output::Text {
+                    eprintln!("read {} records, wrote {}, skipped {}", stats.read, stats.written, stats.skipped);
+                }
+            };
+            let stats = match file {
+                Some(path) => transfer::import(store, BufReader::new(File::open(path)?), format, conflict, progress)?,
+                None => transfer::import(store, io::stdin().lock(), format, conflict, progress)?,
+            };
+            if output == Output::Json {
+                println!("{}", serde_json::to_string(&stats)?);
             }
             Ok(())
         }
-        ("set", Some(m)) => {
-            // values must be Some(_) else clap would have failed
-            let key = m.value_of("key").unwrap();
-            let value = m.value_of("value").unwrap();
-            store.set(key.to_owned(), value.to_owned())?;
+        Command::Keys { prefix, pattern, format } => {
+            let format = output.listing(format);
+            let mut out = BufWriter::new(io::stdout().lock());
+            for key in store.keys()? {
+                if !prefix.as_ref().is_none_or(|p| key.starts_with(p.as_str()))
+                    || !pattern.as_ref().is_none_or(|p| glob_match(p, &key)) {
+                    continue;
+                }
+                write_key(&mut out, format, &key)?;
+            }
+            out.flush()?;
             Ok(())
         }
-        ("rm", Some(m)) => {
-            // values must be Some(_) else clap would have failed
-            let key = m.value_of("key").unwrap();
-            store.remove(key.to_owned())?;
+        Command::Scan { from, to, limit, format } => {
+            let format = output.listing(format);
+            let from = from.map_or(Bound::Unbounded, Bound::Included);
+            let to = to.map_or(Bound::Unbounded, Bound::Excluded);
+            let mut out = BufWriter::new(io::stdout().lock());
+            for pair in store.scan((from, to))?.take(limit.unwrap_or(usize::MAX)) {
+                let (key, value) = pair?;
+                write_pair(&mut out, format, &key, &value)?;
+            }
+            out.flush()?;
             Ok(())
         }
-        _ => unreachable!(),
+        Command::Count { prefix } => {
+            let count = match prefix {
+                Some(prefix) => store.keys()?.iter().filter(|k| k.starts_with(prefix.as_str())).count(),
+                None => store.len(),
+            };
+            match output {
+                Output::Json => println!("{}", json!({ "count": count })),
+                Output::Text => println!("{}", count),
+            }
+            Ok(())
+        }
+        Command::Stats { json } => {
+            let output = if json { Output::Json } else { output };
+            print_stats(store, output)
+        }
+        Command::Backup { dir } => {
+            store.backup_to(dir)?;
+            output.done();
+            Ok(())
+        }
+        Command::Shell { batch } => {
+            match batch {
+                true => shell_batch(store, output),
+                false => shell(store, output),
+            }
+        }
+        Command::Verify { .. } | Command::Restore { .. } | Command::Migrate => unreachable!(),
     }
 }
 
 
 fn main() {
-    if let Err(ref err) = run() {
-        use std::io::Write;
-        let stderr = &mut ::std::io::stderr();
-        writeln!(stderr, "error: {}", err).expect("Error writing to stderr");
-        if err.is_key_not_found() {
-            println!("{}", err);
-        }
-        ::std::process::exit(1);
+    let opts = Kvs::from_args();
+    let output = opts.output;
+    if let Err(ref err) = run(opts) {
+        output.error(err);
+        process::exit(exit_code(err));
     }
 }
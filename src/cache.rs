@@ -0,0 +1,162 @@
+use std::{
+    self,
+    collections::{BTreeMap, HashMap},
+};
+use serde::Serialize;
+
+
+// ~~~~~ CacheStats ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+
+impl CacheStats {
+
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        match lookups {
+            0 => 0.0,
+            _ => self.hits as f64 / lookups as f64,
+        }
+    }
+
+}
+
+
+// ~~~~~ ValueCache ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+#[derive(Debug)]
+struct CacheEntry {
+    value: String,
+    tick: u64,
+}
+
+
+// A byte-bounded LRU cache of decoded values.
+// The size of an entry is the length of its key plus the length of its value,
+// `recency` maps the last access tick of every entry back to its key so the
+// least recently used entry is always the first one in the map.
+#[derive(Debug)]
+pub struct ValueCache {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    entries: HashMap<String, CacheEntry>,
+    recency: BTreeMap<u64, String>,
+    hits: u64,
+    misses: u64,
+}
+
+
+impl ValueCache {
+
+    pub fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        let tick = self.next_tick();
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.recency.remove(&entry.tick);
+                self.recency.insert(tick, key.to_owned());
+                entry.tick = tick;
+                self.hits += 1;
+                Some(entry.value.clone())
+            },
+            None => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+
+    pub fn insert(&mut self, key: String, value: String) {
+        self.remove(&key);
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            // the value would evict everything else and still not fit
+            return;
+        }
+        let tick = self.next_tick();
+        self.size += size;
+        self.recency.insert(tick, key.clone());
+        self.entries.insert(key, CacheEntry { value, tick });
+        self.evict();
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.tick);
+            self.size -= key.len() + entry.value.len();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.size = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+            size: self.size,
+            capacity: self.capacity,
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            let tick = match self.recency.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            if let Some(key) = self.recency.remove(&tick) {
+                if let Some(entry) = self.entries.remove(&key) {
+                    self.size -= key.len() + entry.value.len();
+                }
+            }
+        }
+    }
+
+}
@@ -0,0 +1,345 @@
+use std::{
+    self,
+    io::{BufReader, BufWriter, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    thread,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
+use serde_json::{self, de::IoRead, StreamDeserializer};
+
+use crate::error::*;
+use crate::protocol::{Request, Response};
+use crate::raft::{Message, NodeStatus, Reply};
+use crate::replication::{Position, Records};
+use crate::transport::Stream;
+
+
+// How often a request is sent again after a cluster node redirected it.
+pub(crate) const MAX_REDIRECTS: usize = 20;
+
+// How long to wait before retrying while a cluster elects a leader.
+pub(crate) const ELECTION_WAIT: Duration = Duration::from_millis(100);
+
+
+type Reader = StreamDeserializer<'static, IoRead<BufReader<Box<dyn Read + Send>>>, Response>;
+
+
+// How the client connected, a redirect to the leader connects the same way.
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    Tcp { timeout: Option<Duration> },
+    #[cfg(unix)]
+    Unix,
+}
+
+
+// A connection to a `KvsServer`. Requests are sent one at a time, or many at
+// once with a `Pipeline`, and the connection is reused for all of them. Writes
+// sent to a cluster node that is not the leader are sent again to the leader.
+pub struct KvsClient {
+    reader: Reader,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    // to unblock the reader or the writer of a pipeline when the other fails
+    stream: Box<dyn Stream>,
+    transport: Transport,
+    next_id: u64,
+}
+
+
+impl KvsClient {
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
+        KvsClient::from_stream(TcpStream::connect(addr)?, Transport::Tcp { timeout: None })
+    }
+
+    // Connect and fail reads and writes that take longer than `timeout`.
+    pub fn connect_timeout(addr: SocketAddr, timeout: Duration) -> Result<KvsClient> {
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        KvsClient::from_stream(stream, Transport::Tcp { timeout: Some(timeout) })
+    }
+
+    // Connect to a server listening on a Unix domain socket.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<KvsClient> {
+        KvsClient::from_stream(UnixStream::connect(path)?, Transport::Unix)
+    }
+
+    fn from_stream<S: Stream>(stream: S, transport: Transport) -> Result<KvsClient> {
+        let read_half: Box<dyn Read + Send> = Box::new(stream.try_clone()?);
+        let reader = serde_json::Deserializer::from_reader(BufReader::new(read_half)).into_iter();
+        let handle = Box::new(stream.try_clone()?);
+        Ok(KvsClient { reader, writer: BufWriter::new(Box::new(stream)), stream: handle, transport, next_id: 0 })
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(&Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.request(&Request::Set { key, value })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(&Request::Remove { key })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.request(&Request::GetMany { keys })? {
+            Response::Values(values) => Ok(values),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Set all pairs with one batch in the server's log.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self.request(&Request::SetMany { pairs })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Remove the keys that exist with one batch, returning how many there were.
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<usize> {
+        match self.request(&Request::RemoveMany { keys })? {
+            Response::Count(count) => Ok(count),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Collect requests to send without waiting for each response.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline { client: self, requests: Vec::new() }
+    }
+
+    // At most `limit` pairs with `from <= key < to` in key order, an open
+    // bound when None.
+    pub fn scan(&mut self, from: Option<String>, to: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        match self.request(&Request::Scan { from, to, limit })? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Fetch records from the primary's log, None when they were compacted.
+    pub fn fetch(&mut self, position: Position, limit: usize) -> Result<Option<Records>> {
+        match self.request(&Request::Fetch { position, limit })? {
+            Response::Records { entries, next } => Ok(Some((entries, next))),
+            Response::Compacted => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    // Stream all live pairs of the primary to `f` in chunks, returning the
+    // position the snapshot is current up to.
+    pub fn snapshot<F>(&mut self, mut f: F) -> Result<Position>
+        where F: FnMut(Vec<(String, String)>) -> Result<()>,
+    {
+        let mut response = self.request(&Request::Snapshot)?;
+        loop {
+            match response {
+                Response::SnapshotChunk(pairs) => f(pairs)?,
+                Response::SnapshotEnd { position } => return Ok(position),
+                response => return Err(unexpected(response)),
+            }
+            response = self.receive()?;
+        }
+    }
+
+    pub fn status(&mut self) -> Result<NodeStatus> {
+        match self.request(&Request::Status)? {
+            Response::Status(status) => Ok(status),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn add_node(&mut self, id: u64, addr: SocketAddr) -> Result<()> {
+        match self.request(&Request::AddNode { id, addr })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn remove_node(&mut self, id: u64) -> Result<()> {
+        match self.request(&Request::RemoveNode { id })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub(crate) fn raft(&mut self, message: Message) -> Result<Reply> {
+        match self.request(&Request::Raft(message))? {
+            Response::Raft(reply) => Ok(reply),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        for _ in 0..MAX_REDIRECTS {
+            serde_json::to_writer(&mut self.writer, request)?;
+            self.writer.flush()?;
+            match self.receive()? {
+                Response::NotLeader(Some(addr)) => *self = self.redirect(addr)?,
+                Response::NotLeader(None) => thread::sleep(ELECTION_WAIT),
+                response => return Ok(response),
+            }
+        }
+        Err(KvsError::NoLeader)
+    }
+
+    fn receive(&mut self) -> Result<Response> {
+        receive(&mut self.reader).and_then(into_result)
+    }
+
+    // A connection to the leader at `addr`. Cluster nodes only listen on TCP,
+    // so a client connected over a Unix socket is never redirected.
+    fn redirect(&self, addr: SocketAddr) -> Result<KvsClient> {
+        match self.transport {
+            Transport::Tcp { timeout: None } => KvsClient::connect(addr),
+            Transport::Tcp { timeout: Some(timeout) } => KvsClient::connect_timeout(addr, timeout),
+            #[cfg(unix)]
+            Transport::Unix => Err(KvsError::Protocol(format!("cannot follow a redirect to {} from a Unix socket", addr))),
+        }
+    }
+
+}
+
+
+fn receive(reader: &mut Reader) -> Result<Response> {
+    match reader.next() {
+        Some(Ok(response)) => Ok(response),
+        Some(Err(err)) => Err(KvsError::from(err)),
+        None => Err(KvsError::Protocol("the server closed the connection".to_owned())),
+    }
+}
+
+
+// The errors the server answered with.
+fn into_result(response: Response) -> Result<Response> {
+    match response {
+        Response::KeyNotFound => Err(KvsError::KeyNotFound),
+        Response::Err(msg) => Err(KvsError::Remote(msg)),
+        response => Ok(response),
+    }
+}
+
+
+// ~~~~~ Pipeline ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Requests sent together, each tagged with an id the server answers with. The
+// requests are written while the responses are read, so a long pipeline does
+// not fill up the connection in both directions.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+
+impl Pipeline<'_> {
+
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.push(Request::Get { key })
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.push(Request::Set { key, value })
+    }
+
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.push(Request::Remove { key })
+    }
+
+    pub fn get_many(&mut self, keys: Vec<String>) -> &mut Self {
+        self.push(Request::GetMany { keys })
+    }
+
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> &mut Self {
+        self.push(Request::SetMany { pairs })
+    }
+
+    pub fn remove_many(&mut self, keys: Vec<String>) -> &mut Self {
+        self.push(Request::RemoveMany { keys })
+    }
+
+    fn push(&mut self, request: Request) -> &mut Self {
+        self.requests.push(request);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    // Send the requests and return their responses in the same order. The
+    // result of a request fails when the server answered it with an error.
+    pub fn execute(self) -> Result<Vec<Result<Response>>> {
+        let Pipeline { client, requests } = self;
+        let first_id = client.next_id;
+        client.next_id += requests.len() as u64;
+        let (reader, writer, stream) = (&mut client.reader, &mut client.writer, &client.stream);
+        let (sent, received) = thread::scope(|scope| {
+            let sender = scope.spawn(|| -> Result<()> {
+                let result = (first_id..).zip(&requests)
+                    .try_for_each(|(id, request)| {
+                        serde_json::to_writer(&mut *writer, &Request::Tagged { id, request: Box::new(request.clone()) })
+                    })
+                    .map_err(KvsError::from)
+                    .and_then(|_| writer.flush().map_err(KvsError::from));
+                if result.is_err() {
+                    // the reader waits for responses that will never come
+                    let _ = stream.shutdown();
+                }
+                result
+            });
+            let mut responses: Vec<Option<Response>> = vec![None; requests.len()];
+            let received = (0..requests.len()).try_for_each(|_| match receive(reader)? {
+                Response::Tagged { id, response } if id >= first_id && id - first_id < requests.len() as u64 => {
+                    responses[(id - first_id) as usize] = Some(*response);
+                    Ok(())
+                },
+                response => Err(unexpected(response)),
+            });
+            if received.is_err() {
+                // the writer may be blocked on a server that stopped reading
+                let _ = stream.shutdown();
+            }
+            (sender.join().expect("the pipeline writer panicked"), received.map(|_| responses))
+        });
+        // a failed reader also fails the writer, its error is the one to report
+        let received = received?;
+        sent?;
+        let mut results = Vec::with_capacity(requests.len());
+        for (request, response) in requests.iter().zip(received) {
+            let result = match response {
+                // a write redirected by a cluster node is sent again on its own
+                Some(Response::NotLeader(_)) => client.request(request),
+                Some(response) => into_result(response),
+                None => Err(KvsError::Protocol("a pipelined request was not answered".to_owned())),
+            };
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+}
+
+
+pub(crate) fn unexpected(response: Response) -> KvsError {
+    KvsError::Protocol(format!("unexpected response {:?}", response))
+}
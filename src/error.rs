@@ -14,8 +14,37 @@ pub type Result<T> = std::result::Result<T, KvsError>;
 pub enum KvsError {
     Io(::std::io::Error),
     Serde(serde_json::Error),
+    Csv(csv::Error),
+    ThreadPool(rayon::ThreadPoolBuildError),
     KeyNotFound,
     InvalidLogFileHandle,
+    // The pointer does not refer to a partition or not to a Set record.
+    InvalidLogPointer { partition: u32, offset: u64 },
+    EntryTooLarge,
+    // The record at `offset` in the partition file does not decode.
+    Corruption { file_id: u128, offset: u64 },
+    // A partition listed in `logparts` has no file.
+    MissingPartition { file_id: u128 },
+    // The `logparts` meta data does not decode.
+    CorruptMeta(serde_json::Error),
+    UnsupportedFormatVersion { found: u32, supported: u32 },
+    // The changes after `since` are no longer in the log.
+    ChangesCompacted { since: u64, compacted: u64 },
+    // An error reported by the server.
+    Remote(String),
+    // The peer sent something the protocol does not allow.
+    Protocol(String),
+    // A thread panicked while holding the store.
+    Poisoned,
+    // No cluster node knows a leader to send the request to.
+    NoLeader,
+    Locked { pid: Option<u32> },
+    ReadOnly,
+    StoreNotFound,
+    StoreExists,
+    InvalidOptions(String),
+    // A value or command given by the user that cannot be used.
+    InvalidInput(String),
 }
 
 
@@ -23,6 +52,35 @@ impl KvsError {
     pub fn is_key_not_found(&self) -> bool {
         matches!(*self, KvsError::KeyNotFound)
     }
+
+    pub fn is_locked(&self) -> bool {
+        matches!(*self, KvsError::Locked { .. })
+    }
+
+    // Whether the error means the files in the store directory are damaged,
+    // `verify::verify_dir` can repair most of these.
+    pub fn is_corruption(&self) -> bool {
+        matches!(*self,
+            KvsError::InvalidLogPointer { .. }
+            | KvsError::Corruption { .. }
+            | KvsError::MissingPartition { .. }
+            | KvsError::CorruptMeta(_))
+    }
+
+    // Decoding errors from a record are corruption, errors reading it are not.
+    pub(crate) fn from_record(err: serde_json::Error, file_id: u128, offset: u64) -> KvsError {
+        match err.is_io() {
+            true => KvsError::Io(err.into()),
+            false => KvsError::Corruption { file_id, offset },
+        }
+    }
+
+    pub(crate) fn from_partition_io(err: io::Error, file_id: u128) -> KvsError {
+        match err.kind() {
+            io::ErrorKind::NotFound => KvsError::MissingPartition { file_id },
+            _ => KvsError::Io(err),
+        }
+    }
 }
 
 
@@ -31,8 +89,39 @@ impl fmt::Display for KvsError {
         match *self {
             KvsError::Io(ref err) => err.fmt(f),
             KvsError::Serde(ref err) => err.fmt(f),
+            KvsError::Csv(ref err) => err.fmt(f),
+            KvsError::ThreadPool(ref err) => write!(f, "Failed to start the thread pool: {}", err),
             KvsError::KeyNotFound => write!(f, "Key not found"),
             KvsError::InvalidLogFileHandle => write!(f, "The Log file handle is not valid"),
+            KvsError::InvalidLogPointer { partition, offset } => {
+                write!(f, "The Log pointer to partition {} offset {} does not refer to a record", partition, offset)
+            },
+            KvsError::EntryTooLarge => write!(f, "The Log entry is too large"),
+            KvsError::Corruption { file_id, offset } => {
+                write!(f, "Corrupt record in partition {:016x}.dblog at offset {}", file_id, offset)
+            },
+            KvsError::MissingPartition { file_id } => write!(f, "The partition file {:016x}.dblog is missing", file_id),
+            KvsError::CorruptMeta(ref err) => write!(f, "The logparts meta data is corrupt: {}", err),
+            KvsError::UnsupportedFormatVersion { found, supported } if found < supported => {
+                write!(f, "The store has format version {}, run `kvs migrate` to upgrade it to {}", found, supported)
+            },
+            KvsError::UnsupportedFormatVersion { found, supported } => {
+                write!(f, "The store has format version {}, this version supports up to {}", found, supported)
+            },
+            KvsError::Remote(ref msg) => write!(f, "{}", msg),
+            KvsError::Protocol(ref msg) => write!(f, "Protocol error: {}", msg),
+            KvsError::Poisoned => write!(f, "A thread panicked while using the store"),
+            KvsError::NoLeader => write!(f, "The cluster has no leader"),
+            KvsError::ChangesCompacted { since, compacted } => {
+                write!(f, "The changes after {} were compacted, the log only holds the changes after {}", since, compacted)
+            },
+            KvsError::Locked { pid: Some(pid) } => write!(f, "The store is locked by process {}", pid),
+            KvsError::Locked { pid: None } => write!(f, "The store is locked by another process"),
+            KvsError::ReadOnly => write!(f, "The store is opened read-only"),
+            KvsError::StoreNotFound => write!(f, "No store found in the directory"),
+            KvsError::StoreExists => write!(f, "A store already exists in the directory"),
+            KvsError::InvalidOptions(ref msg) => write!(f, "Invalid options: {}", msg),
+            KvsError::InvalidInput(ref msg) => write!(f, "{}", msg),
         }
     }
 }
@@ -43,6 +132,9 @@ impl Error for KvsError {
         match self {
             KvsError::Io(ref err) => Some(err),
             KvsError::Serde(ref err) => Some(err),
+            KvsError::Csv(ref err) => Some(err),
+            KvsError::CorruptMeta(ref err) => Some(err),
+            KvsError::ThreadPool(ref err) => Some(err),
             _ => None,
         }
     }
@@ -61,3 +153,17 @@ impl From<serde_json::Error> for KvsError {
         KvsError::Serde(err)
     }
 }
+
+
+impl From<csv::Error> for KvsError {
+    fn from(err: csv::Error) -> KvsError {
+        KvsError::Csv(err)
+    }
+}
+
+
+impl From<rayon::ThreadPoolBuildError> for KvsError {
+    fn from(err: rayon::ThreadPoolBuildError) -> KvsError {
+        KvsError::ThreadPool(err)
+    }
+}
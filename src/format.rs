@@ -0,0 +1,252 @@
+use std::{
+    self,
+    io::{self, Read, Write},
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+};
+use serde::{Serialize, Deserialize};
+use serde_json;
+use time::OffsetDateTime;
+
+use crate::error::*;
+use crate::lock::{DirLock, LockMode};
+use crate::log::{LogConfig, LogPartition};
+
+
+// The version of the manifest and partition files written by this crate.
+// Version 1 stores have a `logparts` file without a version and partitions
+// without a header, `migrate` rewrites them.
+pub const FORMAT_VERSION: u32 = 2;
+
+const MAGIC: &[u8; 4] = b"KVSP";
+
+// The length of the header at the start of every partition file, records
+// start right after it.
+pub const HEADER_LEN: u64 = 24;
+
+
+pub(crate) fn meta_file_path(dirname: &Path) -> PathBuf {
+    let mut path = PathBuf::from(dirname);
+    path.push("logparts");
+    path
+}
+
+
+// ~~~~~ PartitionHeader ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Layout, integers are little endian:
+//   [0..4]    magic "KVSP"
+//   [4..8]    format version
+//   [8..16]   reserved
+//   [16..24]  creation time as a unix timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionHeader {
+    pub version: u32,
+    pub created: i64,
+}
+
+
+impl PartitionHeader {
+
+    pub(crate) fn new() -> PartitionHeader {
+        PartitionHeader {
+            version: FORMAT_VERSION,
+            created: OffsetDateTime::now_utc().unix_timestamp(),
+        }
+    }
+
+    fn encode(&self) -> [u8; HEADER_LEN as usize] {
+        let mut buf = [0_u8; HEADER_LEN as usize];
+        buf[0..4].copy_from_slice(MAGIC);
+        buf[4..8].copy_from_slice(&self.version.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.created.to_le_bytes());
+        buf
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        writer.write_all(&self.encode())?;
+        Ok(())
+    }
+
+    // Read and check the header of the partition with `file_id`.
+    pub fn read_from<R: Read>(mut reader: R, file_id: u128) -> Result<PartitionHeader> {
+        let mut buf = [0_u8; HEADER_LEN as usize];
+        reader.read_exact(&mut buf).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => KvsError::Corruption { file_id, offset: 0 },
+            _ => KvsError::Io(err),
+        })?;
+        if &buf[0..4] != MAGIC {
+            return Err(KvsError::Corruption { file_id, offset: 0 });
+        }
+        let version = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if version != FORMAT_VERSION {
+            return Err(KvsError::UnsupportedFormatVersion { found: version, supported: FORMAT_VERSION });
+        }
+        let mut created = [0_u8; 8];
+        created.copy_from_slice(&buf[16..24]);
+        Ok(PartitionHeader { version, created: i64::from_le_bytes(created) })
+    }
+
+}
+
+
+// ~~~~~ Manifest ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// The contents of `logparts`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Manifest {
+    pub format_version: u32,
+    pub config: LogConfig,
+    pub last_compaction: Option<i64>,
+    // The generation counter for partition file ids.
+    #[serde(default)]
+    pub next_file_id: u128,
+    // Changes up to this sequence number were discarded by compaction.
+    #[serde(default)]
+    pub compacted_seq: u64,
+    pub active: LogPartition,
+    pub hist: Vec<LogPartition>,
+}
+
+
+// `logparts` as written before the format was versioned, a dump of the Log.
+#[derive(Deserialize, Debug)]
+struct LegacyManifest {
+    active: LogPartition,
+    hist: Vec<LogPartition>,
+    #[serde(default)]
+    config: LogConfig,
+    #[serde(default)]
+    last_compaction: Option<i64>,
+}
+
+
+fn meta_error(err: serde_json::Error) -> KvsError {
+    match err.is_io() {
+        true => KvsError::Io(err.into()),
+        false => KvsError::CorruptMeta(err),
+    }
+}
+
+
+// The format version of the store in `dirname`.
+pub fn format_version(dirname: &Path) -> Result<u32> {
+    let fh = File::open(meta_file_path(dirname))?;
+    let meta: serde_json::Value = serde_json::from_reader(io::BufReader::new(fh)).map_err(meta_error)?;
+    match meta.get("format_version") {
+        None => Ok(1),
+        Some(version) => {
+            let version = version.as_u64().ok_or_else(|| {
+                KvsError::CorruptMeta(serde::de::Error::custom("format_version is not a number"))
+            })?;
+            Ok(version as u32)
+        },
+    }
+}
+
+
+pub(crate) fn read_manifest(dirname: &Path) -> Result<Manifest> {
+    let version = format_version(dirname)?;
+    if version != FORMAT_VERSION {
+        return Err(KvsError::UnsupportedFormatVersion { found: version, supported: FORMAT_VERSION });
+    }
+    let fh = File::open(meta_file_path(dirname))?;
+    serde_json::from_reader(io::BufReader::new(fh)).map_err(meta_error)
+}
+
+
+// Replace `logparts` by writing a temporary file and renaming it, so a crash
+// leaves either the old or the new manifest. With `sync_dir` the rename, and
+// any partition files created before it, are flushed to disk as well.
+pub(crate) fn write_manifest(dirname: &Path, manifest: &Manifest, sync_dir: bool) -> Result<()> {
+    let path = meta_file_path(dirname);
+    let tmp_path = path.with_extension("tmp");
+    let mut fh = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+    serde_json::to_writer(&mut fh, manifest)?;
+    fh.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    if sync_dir {
+        sync_directory(dirname)?;
+    }
+    Ok(())
+}
+
+
+// Directory entries are only durable once the directory itself is synced.
+#[cfg(unix)]
+fn sync_directory(dirname: &Path) -> Result<()> {
+    File::open(dirname)?.sync_all()?;
+    Ok(())
+}
+
+
+// Windows cannot open a directory as a file and makes renames durable itself.
+#[cfg(not(unix))]
+fn sync_directory(_dirname: &Path) -> Result<()> {
+    Ok(())
+}
+
+
+// ~~~~~ Migration ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Rewrite the store in `dirname` into the current format, returning the
+// version it had. Partitions are rewritten one at a time and `logparts` last,
+// so an interrupted migration can simply be run again.
+pub fn migrate(dirname: &Path) -> Result<u32> {
+    let _lock = DirLock::acquire(dirname, LockMode::Exclusive)?;
+    let version = format_version(dirname)?;
+    match version {
+        FORMAT_VERSION => Ok(version),
+        1 => {
+            migrate_v1(dirname)?;
+            Ok(version)
+        },
+        _ => Err(KvsError::UnsupportedFormatVersion { found: version, supported: FORMAT_VERSION }),
+    }
+}
+
+
+fn migrate_v1(dirname: &Path) -> Result<()> {
+    let fh = File::open(meta_file_path(dirname))?;
+    let legacy: LegacyManifest = serde_json::from_reader(io::BufReader::new(fh)).map_err(meta_error)?;
+    for partition in legacy.hist.iter().chain(Some(&legacy.active)) {
+        // version 1 file ids are the creation time in nanoseconds
+        let created = (partition.file_id() / 1_000_000_000) as i64;
+        let header = PartitionHeader { version: FORMAT_VERSION, created };
+        add_header(partition, dirname, &header)?;
+    }
+    let newest = legacy.hist.iter().chain(Some(&legacy.active)).map(|p| p.file_id()).max();
+    let manifest = Manifest {
+        format_version: FORMAT_VERSION,
+        config: legacy.config,
+        last_compaction: legacy.last_compaction,
+        next_file_id: newest.map_or(1, |id| id + 1),
+        compacted_seq: 0,
+        active: legacy.active,
+        hist: legacy.hist,
+    };
+    write_manifest(dirname, &manifest, true)
+}
+
+
+fn add_header(partition: &LogPartition, dirname: &Path, header: &PartitionHeader) -> Result<()> {
+    let path = partition.full_path(dirname);
+    let mut fh = File::open(&path).map_err(|err| KvsError::from_partition_io(err, partition.file_id()))?;
+    let mut magic = [0_u8; 4];
+    let has_header = match fh.read_exact(&mut magic) {
+        Ok(()) => &magic == MAGIC,
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(err) => return Err(KvsError::Io(err)),
+    };
+    if has_header {
+        // done by an earlier, interrupted migration
+        return Ok(());
+    }
+    let tmp_path = path.with_extension("migrate");
+    let mut tmp = File::create(&tmp_path)?;
+    header.write_to(&mut tmp)?;
+    io::copy(&mut File::open(&path)?, &mut tmp)?;
+    tmp.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,229 @@
+use std::{
+    self,
+    io::Read,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+};
+use serde::Deserialize;
+use serde_json::{self, json};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::error::*;
+use crate::resp::Expiries;
+use crate::KvStore;
+
+
+// Largest request body accepted for a value.
+const MAX_BODY_LEN: u64 = 64 * 1024 * 1024;
+
+
+// The body of `PUT /keys/{key}`.
+#[derive(Deserialize, Debug)]
+struct PutBody {
+    value: String,
+}
+
+
+// ~~~~~ HttpError ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Sent as `{"error": message, "kind": kind}` with the status code.
+#[derive(Debug)]
+struct HttpError {
+    status: u16,
+    kind: &'static str,
+    message: String,
+}
+
+
+impl HttpError {
+
+    fn new(status: u16, kind: &'static str, message: &str) -> HttpError {
+        HttpError { status, kind, message: message.to_owned() }
+    }
+
+}
+
+
+impl From<KvsError> for HttpError {
+    fn from(err: KvsError) -> HttpError {
+        let (status, kind) = match err {
+            KvsError::KeyNotFound => (404, "key_not_found"),
+            KvsError::ReadOnly => (403, "read_only"),
+            KvsError::InvalidOptions(_) => (400, "invalid_options"),
+            KvsError::EntryTooLarge => (413, "entry_too_large"),
+            KvsError::Poisoned => (500, "poisoned"),
+            ref err if err.is_corruption() => (500, "corruption"),
+            KvsError::Io(_) => (500, "io"),
+            _ => (500, "internal"),
+        };
+        HttpError { status, kind, message: err.to_string() }
+    }
+}
+
+
+// Decode `%XX` escapes, and `+` as a space in query strings.
+fn percent_decode(s: &str, query: bool) -> std::result::Result<String, HttpError> {
+    let invalid = || HttpError::new(400, "bad_request", "invalid percent encoding");
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = s.get(i + 1..i + 3).ok_or_else(invalid)?;
+                decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+                i += 3;
+            },
+            b'+' if query => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+
+fn query_param(query: &str, name: &str) -> std::result::Result<Option<String>, HttpError> {
+    for pair in query.split('&') {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(name) {
+            return percent_decode(parts.next().unwrap_or(""), true).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+
+// ~~~~~ HttpHandler ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Serves the REST interface:
+//
+//   GET    /keys/{key}     {"key": .., "value": ..}, 404 when not found
+//   PUT    /keys/{key}     body {"value": ..}
+//   DELETE /keys/{key}     404 when not found
+//   GET    /keys?prefix=   {"keys": [..]} in key order
+//   GET    /stats          the store statistics
+//   POST   /compact
+//
+// Keys in the path are percent-encoded. Writes answer 204 No Content.
+#[derive(Clone)]
+pub(crate) struct HttpHandler {
+    pub store: Arc<Mutex<KvStore>>,
+    pub expiries: Arc<Mutex<Expiries>>,
+    pub follower: bool,
+}
+
+
+impl HttpHandler {
+
+    // Handle the requests on `addr`, each one on its own thread.
+    pub fn serve(self, addr: SocketAddr) -> Result<()> {
+        let server = Server::http(addr).map_err(|err| KvsError::Protocol(format!("failed to serve HTTP: {}", err)))?;
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let handler = self.clone();
+                thread::spawn(move || {
+                    if let Err(err) = handler.respond(request) {
+                        eprintln!("HTTP request failed: {}", err);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn respond(&self, mut request: Request) -> Result<()> {
+        let (status, body) = match self.handle(&mut request) {
+            Ok((status, body)) => (status, body),
+            Err(err) => (err.status, Some(json!({ "error": err.message, "kind": err.kind }))),
+        };
+        let response = match body {
+            Some(body) => Response::from_string(body.to_string())
+                .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).expect("a valid header")),
+            None => Response::from_string(String::new()),
+        };
+        request.respond(response.with_status_code(status))?;
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, KvStore>> {
+        self.store.lock().map_err(|_| KvsError::Poisoned)
+    }
+
+    fn check_writable(&self) -> std::result::Result<(), HttpError> {
+        match self.follower {
+            true => Err(HttpError::new(403, "read_only", "the server is a read-only follower")),
+            false => Ok(()),
+        }
+    }
+
+    fn clear_expiry(&self, key: &str) -> Result<()> {
+        self.expiries.lock().map_err(|_| KvsError::Poisoned)?.set(key, None);
+        Ok(())
+    }
+
+    fn handle(&self, request: &mut Request) -> std::result::Result<(u16, Option<serde_json::Value>), HttpError> {
+        let url = request.url().to_owned();
+        let (path, query) = match url.find('?') {
+            Some(i) => (&url[..i], &url[i + 1..]),
+            None => (url.as_str(), ""),
+        };
+        let method = request.method().clone();
+        if let Some(key) = path.strip_prefix("/keys/") {
+            let key = percent_decode(key, false)?;
+            return match method {
+                Method::Get => match self.lock()?.get(key.clone())? {
+                    Some(value) => Ok((200, Some(json!({ "key": key, "value": value })))),
+                    None => Err(HttpError::from(KvsError::KeyNotFound)),
+                },
+                Method::Put => {
+                    self.check_writable()?;
+                    let mut body = String::new();
+                    request.as_reader().take(MAX_BODY_LEN).read_to_string(&mut body).map_err(KvsError::from)?;
+                    let body: PutBody = serde_json::from_str(&body)
+                        .map_err(|err| HttpError::new(400, "bad_request", &format!("invalid body: {}", err)))?;
+                    let mut store = self.lock()?;
+                    self.clear_expiry(&key)?;
+                    store.set(key, body.value)?;
+                    Ok((204, None))
+                },
+                Method::Delete => {
+                    self.check_writable()?;
+                    let mut store = self.lock()?;
+                    self.clear_expiry(&key)?;
+                    store.remove(key)?;
+                    Ok((204, None))
+                },
+                _ => Err(HttpError::new(405, "method_not_allowed", "use GET, PUT or DELETE")),
+            };
+        }
+        match (method, path) {
+            (Method::Get, "/keys") => {
+                let prefix = query_param(query, "prefix")?.unwrap_or_default();
+                let keys: Vec<String> = self.lock()?.keys()?.into_iter()
+                    .filter(|key| key.starts_with(&prefix))
+                    .collect();
+                Ok((200, Some(json!({ "keys": keys }))))
+            },
+            (Method::Get, "/stats") => {
+                let stats = self.lock()?.stats()?;
+                Ok((200, Some(serde_json::to_value(stats).map_err(KvsError::from)?)))
+            },
+            (Method::Post, "/compact") => {
+                self.lock()?.compact()?;
+                Ok((204, None))
+            },
+            (_, "/keys") | (_, "/stats") | (_, "/compact") => {
+                Err(HttpError::new(405, "method_not_allowed", "method not allowed"))
+            },
+            _ => Err(HttpError::new(404, "not_found", "no such resource")),
+        }
+    }
+
+}
@@ -0,0 +1,763 @@
+// #![allow(dead_code)]
+// #![allow(unused_variables)]
+// #![allow(unused_imports)]
+
+use std::{
+    self,
+    mem,
+    cmp::Ordering,
+    convert::TryInto,
+    io::{BufReader, BufWriter, Read, Write, Seek, SeekFrom},
+    fs::{self, File, OpenOptions},
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use serde::{Serialize, Deserialize};
+use serde_json;
+
+use crate::error::*;
+use crate::log::{Entry, Log, LogPointer};
+
+
+type KvsEntry = Entry<String, String>;
+
+
+// Number of records sorted in memory per run while building a spilled index.
+const SPILL_RUN_CAPACITY: usize = 1 << 16;
+
+// Number of keys written since the sorted file was built after which they are
+// merged into it, so the overlay does not grow without bound.
+const OVERLAY_CAPACITY: usize = 1 << 14;
+
+// Size in bytes of a record in the sorted index file: hash, partition, len, offset.
+const SORTED_RECORD_SIZE: u64 = 24;
+
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u64 = 7;
+
+
+fn sorted_file_path(dirname: &Path) -> PathBuf {
+    let mut path = PathBuf::from(dirname);
+    path.push("index.sorted");
+    path
+}
+
+
+fn run_file_path(dirname: &Path, run: usize) -> PathBuf {
+    let mut path = PathBuf::from(dirname);
+    path.push(format!("index.run.{}", run));
+    path
+}
+
+
+// FNV-1a, used instead of the std hasher because its output is stable.
+pub fn hash_key(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+
+// Read the key of the record `lp` points to, used to verify hashed lookups.
+fn read_entry(log: &Log, lp: &LogPointer) -> Result<KvsEntry> {
+    log.retrieve(lp)
+}
+
+
+// ~~~~~ IndexMode ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IndexMode {
+    // Every key is held in memory.
+    #[default]
+    Full,
+    // Only a 64-bit hash of every key is held in memory, lookups read the
+    // record from the log to verify the key.
+    Hashed,
+    // Keys are kept in a sorted file on disk guarded by a Bloom filter, only
+    // the changes since the index was built are held in memory.
+    Spilled,
+}
+
+
+// ~~~~~ Bucket ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// All pointers for keys sharing the same hash. Almost every bucket holds a
+// single pointer so that case does not pay for a Vec allocation.
+#[derive(Debug)]
+enum Bucket {
+    One(LogPointer),
+    Many(Vec<LogPointer>),
+}
+
+
+impl Bucket {
+
+    fn pointers(&self) -> &[LogPointer] {
+        match self {
+            Bucket::One(lp) => std::slice::from_ref(lp),
+            Bucket::Many(lps) => lps,
+        }
+    }
+
+    fn push(&mut self, lp: LogPointer) {
+        match self {
+            Bucket::One(first) => { *self = Bucket::Many(vec![*first, lp]); },
+            Bucket::Many(lps) => lps.push(lp),
+        }
+    }
+
+    fn replace(&mut self, i: usize, lp: LogPointer) {
+        match self {
+            Bucket::One(first) => { *first = lp; },
+            Bucket::Many(lps) => { lps[i] = lp; },
+        }
+    }
+
+    // Returns false when the bucket is empty afterwards.
+    fn remove(&mut self, i: usize) -> bool {
+        match self {
+            Bucket::One(_) => false,
+            Bucket::Many(lps) => {
+                lps.remove(i);
+                if lps.len() == 1 {
+                    *self = Bucket::One(lps[0]);
+                }
+                true
+            },
+        }
+    }
+
+}
+
+
+// Hashed key to pointers map, verifying keys against the log.
+#[derive(Debug, Default)]
+pub(crate) struct HashedMap {
+    buckets: HashMap<u64, Bucket>,
+}
+
+
+impl HashedMap {
+
+    // Find the record for `key`, returning its position in the bucket and the entry.
+    fn find(&self, log: &Log, hash: u64, key: &str) -> Result<Option<(usize, LogPointer, KvsEntry)>> {
+        if let Some(bucket) = self.buckets.get(&hash) {
+            for (i, lp) in bucket.pointers().iter().enumerate() {
+                let entry = read_entry(log, lp)?;
+                if entry.key() == key {
+                    return Ok(Some((i, *lp, entry)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn upsert(&mut self, log: &Log, hash: u64, key: &str, lp: LogPointer) -> Result<Option<KvsEntry>> {
+        match self.find(log, hash, key)? {
+            Some((i, _, entry)) => {
+                if let Some(bucket) = self.buckets.get_mut(&hash) {
+                    bucket.replace(i, lp);
+                }
+                Ok(Some(entry))
+            },
+            None => {
+                match self.buckets.get_mut(&hash) {
+                    Some(bucket) => bucket.push(lp),
+                    None => { self.buckets.insert(hash, Bucket::One(lp)); },
+                }
+                Ok(None)
+            },
+        }
+    }
+
+    fn delete(&mut self, log: &Log, hash: u64, key: &str) -> Result<Option<KvsEntry>> {
+        match self.find(log, hash, key)? {
+            Some((i, _, entry)) => {
+                let keep = match self.buckets.get_mut(&hash) {
+                    Some(bucket) => bucket.remove(i),
+                    None => false,
+                };
+                if !keep {
+                    self.buckets.remove(&hash);
+                }
+                Ok(Some(entry))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn pointers(&self) -> impl Iterator<Item = (u64, LogPointer)> + '_ {
+        self.buckets.iter().flat_map(|(h, b)| b.pointers().iter().map(move |lp| (*h, *lp)))
+    }
+
+    fn memory_estimate(&self) -> usize {
+        let bucket = mem::size_of::<u64>() + mem::size_of::<Bucket>();
+        let extra: usize = self.buckets.values()
+            .map(|b| match b {
+                Bucket::One(_) => 0,
+                Bucket::Many(lps) => lps.capacity() * mem::size_of::<LogPointer>(),
+            })
+            .sum();
+        self.buckets.capacity() * bucket + extra
+    }
+
+}
+
+
+// ~~~~~ BloomFilter ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+#[derive(Debug)]
+struct BloomFilter {
+    bits: Vec<u64>,
+    nbits: u64,
+}
+
+
+impl BloomFilter {
+
+    fn new(keys: usize) -> BloomFilter {
+        let nbits = ((keys.max(1) * BLOOM_BITS_PER_KEY) as u64).div_ceil(64) * 64;
+        BloomFilter { bits: vec![0; (nbits / 64) as usize], nbits }
+    }
+
+    // Double hashing: the i-th probe is h1 + i * h2.
+    fn probes(&self, hash: u64) -> impl Iterator<Item = u64> {
+        let nbits = self.nbits;
+        let h1 = hash & 0xffff_ffff;
+        let h2 = (hash >> 32) | 1;
+        (0..BLOOM_HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % nbits)
+    }
+
+    fn insert(&mut self, hash: u64) {
+        for bit in self.probes(hash) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    fn may_contain(&self, hash: u64) -> bool {
+        self.probes(hash).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+}
+
+
+// ~~~~~ SortedFile ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Fixed size records sorted by key hash, searched with binary search.
+#[derive(Debug)]
+pub(crate) struct SortedFile {
+    path: PathBuf,
+    fh: File,
+    records: u64,
+    bloom: BloomFilter,
+}
+
+
+impl SortedFile {
+
+    fn encode(hash: u64, lp: &LogPointer) -> [u8; SORTED_RECORD_SIZE as usize] {
+        let mut buf = [0_u8; SORTED_RECORD_SIZE as usize];
+        buf[0..8].copy_from_slice(&hash.to_be_bytes());
+        buf[8..12].copy_from_slice(&lp.partition().to_le_bytes());
+        buf[12..16].copy_from_slice(&(lp.len() as u32).to_le_bytes());
+        buf[16..24].copy_from_slice(&lp.offset().to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> (u64, LogPointer) {
+        let hash = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+        let partition = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        let len = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+        let offset = u64::from_le_bytes(buf[16..24].try_into().unwrap());
+        (hash, LogPointer::new(partition, offset, len))
+    }
+
+    fn open(path: PathBuf) -> Result<SortedFile> {
+        let fh = File::open(&path)?;
+        let records = fh.metadata()?.len() / SORTED_RECORD_SIZE;
+        let mut bloom = BloomFilter::new(records as usize);
+        let mut reader = BufReader::new(&fh);
+        let mut buf = [0_u8; SORTED_RECORD_SIZE as usize];
+        for _ in 0..records {
+            reader.read_exact(&mut buf)?;
+            bloom.insert(SortedFile::decode(&buf).0);
+        }
+        Ok(SortedFile { path, fh, records, bloom })
+    }
+
+    fn read(&self, i: u64) -> Result<(u64, LogPointer)> {
+        let mut fh = &self.fh;
+        let mut buf = [0_u8; SORTED_RECORD_SIZE as usize];
+        fh.seek(SeekFrom::Start(i * SORTED_RECORD_SIZE))?;
+        fh.read_exact(&mut buf)?;
+        Ok(SortedFile::decode(&buf))
+    }
+
+    // All pointers for keys with the given hash.
+    fn lookup(&self, hash: u64) -> Result<Vec<LogPointer>> {
+        let mut found = vec![];
+        if !self.bloom.may_contain(hash) {
+            return Ok(found);
+        }
+        // find the first record with a hash >= `hash`
+        let (mut lo, mut hi) = (0, self.records);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.read(mid)?.0.cmp(&hash) {
+                Ordering::Less => lo = mid + 1,
+                _ => hi = mid,
+            }
+        }
+        while lo < self.records {
+            let (h, lp) = self.read(lo)?;
+            if h != hash {
+                break;
+            }
+            found.push(lp);
+            lo += 1;
+        }
+        Ok(found)
+    }
+
+    // The records in hash order, read one at a time.
+    fn iter(&self) -> Result<impl Iterator<Item = Result<(u64, LogPointer)>> + '_> {
+        let mut fh = &self.fh;
+        fh.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(fh);
+        let mut buf = [0_u8; SORTED_RECORD_SIZE as usize];
+        Ok((0..self.records).map(move |_| {
+            reader.read_exact(&mut buf)?;
+            Ok(SortedFile::decode(&buf))
+        }))
+    }
+
+    fn records(&self) -> Result<Vec<(u64, LogPointer)>> {
+        self.iter()?.collect()
+    }
+
+}
+
+
+impl Drop for SortedFile {
+    fn drop(&mut self) {
+        // the sorted file is rebuilt on every open, failing to remove it is harmless
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+
+// A record collected while replaying the log to build a spilled index.
+#[derive(Serialize, Deserialize, Debug)]
+struct SpillRecord {
+    hash: u64,
+    key: String,
+    seq: u64,
+    lp: LogPointer,
+    removed: bool,
+}
+
+
+impl SpillRecord {
+    fn sort_key(&self) -> (u64, &str, u64) {
+        (self.hash, &self.key, self.seq)
+    }
+}
+
+
+// Build the sorted file for a log with an external merge sort: the log is
+// replayed in runs of SPILL_RUN_CAPACITY records which are sorted in memory
+// and written to disk, then the runs are merged keeping only the last record
+// for every key.
+fn build_sorted_file(log: &Log) -> Result<SortedFile> {
+    let mut runs = vec![];
+    let mut buffer: Vec<SpillRecord> = Vec::with_capacity(SPILL_RUN_CAPACITY);
+    let write_run = |buffer: &mut Vec<SpillRecord>, runs: &mut Vec<PathBuf>| -> Result<()> {
+        buffer.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+        let path = run_file_path(&log.dirname, runs.len());
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for record in buffer.drain(..) {
+            serde_json::to_writer(&mut writer, &record)?;
+        }
+        writer.flush()?;
+        runs.push(path);
+        Ok(())
+    };
+    let mut spill = || -> Result<()> {
+        for (seq, item) in log.iter::<KvsEntry>().enumerate() {
+            let (entry, lp) = item?;
+            let (key, removed) = match entry {
+                Entry::Set(key, _) => (key, false),
+                Entry::Remove(key) => (key, true),
+            };
+            buffer.push(SpillRecord { hash: hash_key(&key), key, seq: seq as u64, lp, removed });
+            if buffer.len() == SPILL_RUN_CAPACITY {
+                write_run(&mut buffer, &mut runs)?;
+            }
+        }
+        write_run(&mut buffer, &mut runs)
+    };
+    let spilled = spill();
+
+    let path = sorted_file_path(&log.dirname);
+    // remove the runs also when reading the log failed half way
+    let result = spilled.and_then(|_| merge_runs(&runs, &path));
+    for run in &runs {
+        fs::remove_file(run)?;
+    }
+    result?;
+    SortedFile::open(path)
+}
+
+
+fn merge_runs(runs: &[PathBuf], path: &Path) -> Result<()> {
+    let mut readers = vec![];
+    for run in runs {
+        let fh = File::open(run)?;
+        readers.push(serde_json::Deserializer::from_reader(BufReader::new(fh)).into_iter::<SpillRecord>());
+    }
+    let mut heads: Vec<Option<SpillRecord>> = vec![];
+    for reader in readers.iter_mut() {
+        heads.push(reader.next().transpose()?);
+    }
+    let fh = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+    let mut writer = BufWriter::new(fh);
+    let mut pending: Option<SpillRecord> = None;
+    loop {
+        // pick the run with the smallest head, the number of runs is small
+        let next = heads.iter().enumerate()
+            .filter_map(|(i, h)| h.as_ref().map(|r| (i, r)))
+            .min_by(|(_, a), (_, b)| a.sort_key().cmp(&b.sort_key()))
+            .map(|(i, _)| i);
+        let i = match next {
+            Some(i) => i,
+            None => break,
+        };
+        let record = mem::replace(&mut heads[i], readers[i].next().transpose()?);
+        let record = match record {
+            Some(record) => record,
+            None => continue,
+        };
+        // records for the same key are adjacent and ordered by sequence number
+        if let Some(prev) = pending.take() {
+            if (prev.hash != record.hash || prev.key != record.key) && !prev.removed {
+                writer.write_all(&SortedFile::encode(prev.hash, &prev.lp))?;
+            }
+        }
+        pending = Some(record);
+    }
+    if let Some(prev) = pending {
+        if !prev.removed {
+            writer.write_all(&SortedFile::encode(prev.hash, &prev.lp))?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+
+// Merge the Set records of `overlay` into the sorted file, dropping the
+// records they or a Remove record in the overlay replace.
+fn flush_overlay(log: &Log, file: &mut Option<SortedFile>, overlay: &mut HashedMap) -> Result<()> {
+    let mut added = vec![];
+    for (hash, lp) in overlay.pointers() {
+        if let Entry::Set(..) = read_entry(log, &lp)? {
+            added.push((hash, lp));
+        }
+    }
+    added.sort_by_key(|(hash, _)| *hash);
+    let mut added = added.into_iter().peekable();
+    let path = sorted_file_path(&log.dirname);
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    if let Some(file) = file {
+        for record in file.iter()? {
+            let (hash, lp) = record?;
+            if overlay.buckets.contains_key(&hash) {
+                let entry = read_entry(log, &lp)?;
+                if overlay.find(log, hash, entry.key())?.is_some() {
+                    continue;
+                }
+            }
+            while let Some((hash, lp)) = added.next_if(|(h, _)| *h < hash) {
+                writer.write_all(&SortedFile::encode(hash, &lp))?;
+            }
+            writer.write_all(&SortedFile::encode(hash, &lp))?;
+        }
+    }
+    for (hash, lp) in added {
+        writer.write_all(&SortedFile::encode(hash, &lp))?;
+    }
+    writer.flush()?;
+    drop(writer);
+    // the old file removes itself when dropped, so it goes before the rename
+    *file = None;
+    fs::rename(&tmp_path, &path)?;
+    *file = Some(SortedFile::open(path)?);
+    *overlay = HashedMap::default();
+    Ok(())
+}
+
+
+// ~~~~~ Index ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+#[derive(Debug)]
+pub(crate) enum Index {
+    Full(HashMap<String, LogPointer>),
+    Hashed {
+        map: HashedMap,
+        count: usize,
+    },
+    Spilled {
+        file: Option<SortedFile>,
+        // Set and Remove records appended since the sorted file was built.
+        overlay: HashedMap,
+        count: usize,
+    },
+}
+
+
+impl Index {
+
+    pub fn new(mode: IndexMode) -> Index {
+        match mode {
+            IndexMode::Full => Index::Full(HashMap::new()),
+            IndexMode::Hashed => Index::Hashed { map: HashedMap::default(), count: 0 },
+            IndexMode::Spilled => Index::Spilled { file: None, overlay: HashedMap::default(), count: 0 },
+        }
+    }
+
+    pub fn mode(&self) -> IndexMode {
+        match self {
+            Index::Full(_) => IndexMode::Full,
+            Index::Hashed { .. } => IndexMode::Hashed,
+            Index::Spilled { .. } => IndexMode::Spilled,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Index::Full(map) => map.len(),
+            Index::Hashed { count, .. } | Index::Spilled { count, .. } => *count,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Rebuild the index by replaying the log.
+    pub fn load(&mut self, log: &Log) -> Result<()> {
+        match self {
+            Index::Spilled { file, overlay, count } => {
+                // drop the previous sorted file before building a new one in its place
+                *file = None;
+                let sorted = build_sorted_file(log)?;
+                *count = sorted.records as usize;
+                *file = Some(sorted);
+                *overlay = HashedMap::default();
+            },
+            _ => {
+                *self = Index::new(self.mode());
+                for item in log.iter::<KvsEntry>() {
+                    let (entry, lp) = item?;
+                    match entry {
+                        Entry::Set(k, _v) => { self.insert(log, k, lp)?; },
+                        Entry::Remove(k) => { self.remove(log, &k, lp)?; },
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    // Returns the pointer to the Set record of a live key.
+    pub fn get(&self, log: &Log, key: &str) -> Result<Option<LogPointer>> {
+        match self {
+            Index::Full(map) => Ok(map.get(key).copied()),
+            Index::Hashed { map, .. } => {
+                Ok(map.find(log, hash_key(key), key)?.map(|(_, lp, _)| lp))
+            },
+            Index::Spilled { file, overlay, .. } => {
+                let hash = hash_key(key);
+                match overlay.find(log, hash, key)? {
+                    Some((_, lp, Entry::Set(..))) => return Ok(Some(lp)),
+                    Some((_, _, Entry::Remove(..))) => return Ok(None),
+                    None => {},
+                }
+                if let Some(file) = file {
+                    for lp in file.lookup(hash)? {
+                        if read_entry(log, &lp)?.key() == key {
+                            return Ok(Some(lp));
+                        }
+                    }
+                }
+                Ok(None)
+            },
+        }
+    }
+
+    pub fn contains_key(&self, log: &Log, key: &str) -> Result<bool> {
+        Ok(self.get(log, key)?.is_some())
+    }
+
+    // Point `key` at the Set record `lp`.
+    pub fn insert(&mut self, log: &Log, key: String, lp: LogPointer) -> Result<()> {
+        match self {
+            Index::Full(map) => { map.insert(key, lp); },
+            Index::Hashed { map, count } => {
+                if map.upsert(log, hash_key(&key), &key, lp)?.is_none() {
+                    *count += 1;
+                }
+            },
+            Index::Spilled { .. } => {
+                let existed = self.contains_key(log, &key)?;
+                if let Index::Spilled { file, overlay, count } = self {
+                    overlay.upsert(log, hash_key(&key), &key, lp)?;
+                    if !existed {
+                        *count += 1;
+                    }
+                    if overlay.buckets.len() >= OVERLAY_CAPACITY {
+                        flush_overlay(log, file, overlay)?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    // Remove `key`, `lp` points to the Remove record appended to the log.
+    pub fn remove(&mut self, log: &Log, key: &str, lp: LogPointer) -> Result<()> {
+        match self {
+            Index::Full(map) => { map.remove(key); },
+            Index::Hashed { map, count } => {
+                if map.delete(log, hash_key(key), key)?.is_some() {
+                    *count -= 1;
+                }
+            },
+            Index::Spilled { .. } => {
+                let existed = self.contains_key(log, key)?;
+                if let Index::Spilled { file, overlay, count } = self {
+                    overlay.upsert(log, hash_key(key), key, lp)?;
+                    if existed {
+                        *count -= 1;
+                    }
+                    if overlay.buckets.len() >= OVERLAY_CAPACITY {
+                        flush_overlay(log, file, overlay)?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    // Pointers to the Set records of all live keys.
+    pub fn pointers(&self, log: &Log) -> Result<Vec<LogPointer>> {
+        match self {
+            Index::Full(map) => Ok(map.values().copied().collect()),
+            Index::Hashed { map, .. } => Ok(map.pointers().map(|(_, lp)| lp).collect()),
+            Index::Spilled { file, overlay, .. } => {
+                let mut pointers = vec![];
+                if let Some(file) = file {
+                    for (hash, lp) in file.records()? {
+                        // skip records shadowed by a later write to the same key
+                        if overlay.buckets.contains_key(&hash) {
+                            let entry = read_entry(log, &lp)?;
+                            if overlay.find(log, hash, entry.key())?.is_some() {
+                                continue;
+                            }
+                        }
+                        pointers.push(lp);
+                    }
+                }
+                for (_, lp) in overlay.pointers() {
+                    if let Entry::Set(..) = read_entry(log, &lp)? {
+                        pointers.push(lp);
+                    }
+                }
+                Ok(pointers)
+            },
+        }
+    }
+
+    // The keys of all live entries with the pointers to their Set records.
+    pub fn key_pointers(&self, log: &Log) -> Result<Vec<(String, LogPointer)>> {
+        match self {
+            Index::Full(map) => Ok(map.iter().map(|(k, lp)| (k.clone(), *lp)).collect()),
+            _ => {
+                let mut pairs = vec![];
+                for lp in self.pointers(log)? {
+                    if let Entry::Set(key, _) = read_entry(log, &lp)? {
+                        pairs.push((key, lp));
+                    }
+                }
+                Ok(pairs)
+            },
+        }
+    }
+
+    // Index entries that do not point at a matching record in the log.
+    pub fn dangling(&self, log: &Log) -> Result<Vec<(Option<String>, LogPointer)>> {
+        // a pointer matches when it points at a record for the key or key hash
+        let matches = |lp: &LogPointer, key: Option<&str>, hash: u64, tombstone: bool| {
+            match read_entry(log, lp) {
+                Ok(Entry::Set(k, _)) => key.map_or(hash_key(&k) == hash, |key| k == key),
+                Ok(Entry::Remove(k)) => tombstone && hash_key(&k) == hash,
+                Err(_) => false,
+            }
+        };
+        let mut dangling = vec![];
+        match self {
+            Index::Full(map) => {
+                for (key, lp) in map {
+                    if !matches(lp, Some(key), 0, false) {
+                        dangling.push((Some(key.clone()), *lp));
+                    }
+                }
+            },
+            Index::Hashed { map, .. } => {
+                for (hash, lp) in map.pointers() {
+                    if !matches(&lp, None, hash, false) {
+                        dangling.push((None, lp));
+                    }
+                }
+            },
+            Index::Spilled { file, overlay, .. } => {
+                let records = match file {
+                    Some(file) => file.records()?,
+                    None => vec![],
+                };
+                for (hash, lp) in records {
+                    if !matches(&lp, None, hash, false) {
+                        dangling.push((None, lp));
+                    }
+                }
+                for (hash, lp) in overlay.pointers() {
+                    if !matches(&lp, None, hash, true) {
+                        dangling.push((None, lp));
+                    }
+                }
+            },
+        }
+        Ok(dangling)
+    }
+
+    // Estimate of the heap memory held by the index in bytes.
+    pub fn memory_estimate(&self) -> usize {
+        match self {
+            Index::Full(map) => {
+                let slot = mem::size_of::<String>() + mem::size_of::<LogPointer>();
+                map.capacity() * slot + map.keys().map(|k| k.capacity()).sum::<usize>()
+            },
+            Index::Hashed { map, .. } => map.memory_estimate(),
+            Index::Spilled { file, overlay, .. } => {
+                let bloom = file.as_ref().map_or(0, |f| f.bloom.bits.len() * mem::size_of::<u64>());
+                bloom + overlay.memory_estimate()
+            },
+        }
+    }
+
+}
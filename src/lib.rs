@@ -4,103 +4,437 @@
 
 use std::{
     self,
-    collections::HashMap,
+    fs,
+    vec,
+    collections::{HashMap, HashSet},
+    ops::RangeBounds,
     path::Path,
 };
 
 
+#[cfg(feature = "async")]
+pub mod async_client;
+#[cfg(feature = "async")]
+pub mod async_server;
+#[cfg(feature = "async")]
+pub mod async_store;
+pub mod backup;
+pub mod batch;
+pub mod cache;
+pub mod client;
 pub mod error;
+pub mod format;
+#[cfg(feature = "http")]
+mod http;
+pub mod index;
+pub mod lock;
 pub mod log;
+pub mod options;
+pub mod protocol;
+pub mod raft;
+pub mod replication;
+pub mod resp;
+pub mod scan;
+pub mod server;
+pub mod shard;
+pub mod stats;
+pub mod thread_pool;
+pub mod transfer;
+mod transport;
+pub mod verify;
+pub mod watch;
 
+#[cfg(feature = "async")]
+pub use async_client::AsyncKvsClient;
+#[cfg(feature = "async")]
+pub use async_server::AsyncKvsServer;
+#[cfg(feature = "async")]
+pub use async_store::AsyncKvStore;
+pub use batch::WriteBatch;
+pub use cache::CacheStats;
+pub use client::{KvsClient, Pipeline};
 pub use error::*;
+pub use format::FORMAT_VERSION;
+pub use index::IndexMode;
+pub use options::{CompactionPolicy, KvStoreOptions, SyncPolicy};
+pub use raft::{Member, NodeStatus, Role};
+pub use replication::Position;
+pub use scan::{glob_match, Scan};
+pub use server::{KvsServer, ShutdownHandle};
+pub use shard::{HashRing, RebalanceStats, ShardedClient};
+pub use stats::StoreStats;
+pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use verify::{Problem, VerifyReport};
+pub use watch::{Changes, Event, Watcher};
+use cache::ValueCache;
+use index::Index;
+use lock::{DirLock, LockMode};
 use log::{Entry, Log, LogPointer};
+use watch::Subscribers;
 
 
 type KvsEntry = Entry<String, String>;
 
 
-const COMPACTION_FACTOR: usize = 2;
-
-
 #[derive(Debug)]
 pub struct KvStore {
     log: Log,
-    index: HashMap<String, LogPointer>,
+    index: Index,
+    cache: ValueCache,
+    compaction: CompactionPolicy,
+    subscribers: Subscribers,
+    // declared last so the lock is only released after the log wrote its meta data
+    lock: DirLock,
 }
 
 
 impl KvStore {
 
     pub fn open<P: AsRef<Path>>(dirname: P) -> Result<KvStore> {
+        KvStore::open_with_options(dirname, &KvStoreOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(dirname: P, options: &KvStoreOptions) -> Result<KvStore> {
         // eprintln!("KvsStore::open()");
+        options.validate()?;
+        let dirname = dirname.as_ref();
+        let (lock, log) = if options.read_only {
+            if !Log::exists(dirname) {
+                return Err(KvsError::StoreNotFound);
+            }
+            let lock = DirLock::acquire(dirname, LockMode::Shared)?;
+            (lock, Log::open_read_only(dirname, options)?)
+        } else {
+            if !dirname.exists() {
+                if !options.create_if_missing {
+                    return Err(KvsError::StoreNotFound);
+                }
+                fs::create_dir_all(dirname)?;
+            }
+            let lock = DirLock::acquire(dirname, LockMode::Exclusive)?;
+            (lock, Log::open(dirname, options)?)
+        };
         let mut store = KvStore {
-            log: Log::open(dirname.as_ref())?,
-            index: HashMap::new(),
+            log,
+            index: Index::new(options.index_mode),
+            cache: ValueCache::new(options.cache_capacity),
+            compaction: options.compaction,
+            subscribers: Subscribers::default(),
+            lock,
         };
         store.load_index()?;
         // eprintln!("KvsStore::open() -> {:?}", store);
         Ok(store)
     }
 
+    // Write the store's meta data and release the directory lock. Dropping
+    // the store does the same but has to ignore errors.
+    pub fn close(mut self) -> Result<()> {
+        self.log.close()
+    }
+
+    // Flush all writes to disk and write the meta data, whatever the sync
+    // policy. The store stays open.
+    pub fn sync(&mut self) -> Result<()> {
+        self.log.sync()
+    }
+
     pub fn len(&self) -> usize {
         self.index.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    // Open an existing store for reading only. Other readers may open the
+    // store at the same time but writers are locked out until it is dropped.
+    // Nothing in the directory is ever created, appended to or rewritten, and
+    // `set` and `remove` fail with `KvsError::ReadOnly`.
+    pub fn open_read_only<P: AsRef<Path>>(dirname: P) -> Result<KvStore> {
+        KvStore::open_with_options(dirname, &KvStoreOptions::new().read_only(true))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.log.dirname
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.log.is_read_only()
+    }
+
+    pub fn lock_mode(&self) -> LockMode {
+        self.lock.mode()
+    }
+
+    pub fn index_mode(&self) -> IndexMode {
+        self.index.mode()
+    }
+
+    // Estimate of the memory held by the index in bytes.
+    pub fn index_memory(&self) -> usize {
+        self.index.memory_estimate()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    // A capacity of 0 disables the value cache.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache.set_capacity(capacity);
+    }
+
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
         // eprintln!("KvsStore::set()");
-        let entry = KvsEntry::Set(key.clone(), value.clone());
+        if self.is_read_only() {
+            return Err(KvsError::ReadOnly);
+        }
+        let entry = Entry::Set(&key, &value);
         let log_pointer = self.log.append(&entry)?;
-        self.index.insert(key, log_pointer);
+        self.index.insert(&self.log, key.clone(), log_pointer)?;
+        if !self.subscribers.is_empty() {
+            let seq = self.log.last_seq();
+            self.subscribers.publish(&Event::Set { seq, key: key.clone(), value: value.clone() });
+        }
+        self.cache.insert(key, value);
         self.maybe_compact()?;
         Ok(())
     }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.index.get(&key) {
-            Some(lp) => {
-                match self.log.retrieve(lp)? {
-                    KvsEntry::Set(_key, value) => Ok(Some(value)),
-                    _ => Err(KvsError::KeyNotFound),
-                }
+        if let Some(value) = self.cache.get(&key) {
+            return Ok(Some(value));
+        }
+        let value = self.read(&key)?;
+        if let Some(ref value) = value {
+            self.cache.insert(key, value.clone());
+        }
+        Ok(value)
+    }
+
+    // The value of `key` read from the log, leaving the cache alone.
+    pub(crate) fn read(&self, key: &str) -> Result<Option<String>> {
+        match self.index.get(&self.log, key)? {
+            Some(lp) => match self.log.retrieve(&lp)? {
+                KvsEntry::Set(_key, value) => Ok(Some(value)),
+                _ => Err(lp.invalid()),
             },
             None => Ok(None),
         }
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        match self.get(key.clone())? {
-            Some(_) => {
-                let entry = KvsEntry::Remove(key.clone());
-                self.log.append(&entry)?;
-                self.index.remove(&key);
-                Ok(())
+    // Apply all writes in the batch with a single append to the log. When a
+    // key to remove does not exist nothing is written and `KeyNotFound` is
+    // returned.
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        if self.is_read_only() {
+            return Err(KvsError::ReadOnly);
+        }
+        // whether a key exists after the writes earlier in the batch
+        let mut exists: HashMap<&str, bool> = HashMap::new();
+        for entry in &batch.entries {
+            if let Entry::Remove(key) = entry {
+                let found = match exists.get(key.as_str()) {
+                    Some(found) => *found,
+                    None => self.contains_key(key)?,
+                };
+                if !found {
+                    return Err(KvsError::KeyNotFound);
+                }
+            }
+            exists.insert(entry.key(), matches!(entry, Entry::Set(..)));
+        }
+        let pointers = self.log.append_batch(&batch.entries)?;
+        let first_seq = self.log.last_seq() + 1 - pointers.len() as u64;
+        for (seq, (entry, lp)) in (first_seq..).zip(batch.entries.into_iter().zip(pointers)) {
+            match entry {
+                Entry::Set(key, value) => {
+                    self.index.insert(&self.log, key.clone(), lp)?;
+                    if !self.subscribers.is_empty() {
+                        self.subscribers.publish(&Event::Set { seq, key: key.clone(), value: value.clone() });
+                    }
+                    self.cache.insert(key, value);
+                },
+                Entry::Remove(key) => {
+                    self.index.remove(&self.log, &key, lp)?;
+                    self.cache.remove(&key);
+                    if !self.subscribers.is_empty() {
+                        self.subscribers.publish(&Event::Remove { seq, key });
+                    }
+                },
             }
-            None => Err(KvsError::KeyNotFound),
         }
+        self.maybe_compact()
     }
 
-    fn load_index(&mut self) -> Result<()> {
-        for item  in self.log.iter::<KvsEntry>() {
-            match item {
-                (KvsEntry::Set(k, _v), lp) => { self.index.insert(k.to_owned(), lp); },
-                (KvsEntry::Remove(k), _lp) => { self.index.remove(&k); },
+    // Set all pairs with a single append to the log.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in pairs {
+            batch.set(key, value);
+        }
+        self.write(batch)
+    }
+
+    // Remove the keys that exist with a single append to the log, returning
+    // how many were removed. Missing and repeated keys are skipped.
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<usize> {
+        let mut batch = WriteBatch::new();
+        let mut removed = HashSet::new();
+        for key in keys {
+            if !removed.contains(&key) && self.contains_key(&key)? {
+                removed.insert(key.clone());
+                batch.remove(key);
             }
         }
+        if !removed.is_empty() {
+            self.write(batch)?;
+        }
+        Ok(removed.len())
+    }
+
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
+        self.index.contains_key(&self.log, key)
+    }
+
+    // Iterate over all live key/value pairs in log order.
+    pub fn iter(&self) -> Result<Iter<'_>> {
+        let mut pointers = self.index.pointers(&self.log)?;
+        pointers.sort_by_key(|lp| (lp.partition(), lp.offset()));
+        Ok(Iter { log: &self.log, pointers: pointers.into_iter() })
+    }
+
+    // All live keys in sorted order.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self.index.key_pointers(&self.log)?.into_iter().map(|(k, _)| k).collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    // Iterate over the key/value pairs with a key in `range`, in key order.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Scan<'_>> {
+        let mut pairs = self.index.key_pointers(&self.log)?;
+        pairs.retain(|(key, _)| range.contains(key));
+        Ok(Scan::new(&self.log, pairs))
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if self.is_read_only() {
+            return Err(KvsError::ReadOnly);
+        }
+        if !self.contains_key(&key)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        let entry = KvsEntry::Remove(key.clone());
+        let log_pointer = self.log.append(&entry)?;
+        self.index.remove(&self.log, &key, log_pointer)?;
+        self.cache.remove(&key);
+        if !self.subscribers.is_empty() {
+            self.subscribers.publish(&Event::Remove { seq: self.log.last_seq(), key });
+        }
+        Ok(())
+    }
+
+    // Rewrite the log keeping only the live entries, regardless of the
+    // compaction policy.
+    pub fn compact(&mut self) -> Result<()> {
+        if self.is_read_only() {
+            return Err(KvsError::ReadOnly);
+        }
+        let pointers = self.index.pointers(&self.log)?;
+        self.log.compact(pointers)?;
+        // rebuild the index
+        self.load_index()?;
+        self.cache.clear();
+        Ok(())
+    }
+
+    // Subscribe to the writes to keys starting with `prefix`. Events are
+    // published once they are appended to the log, use `last_seq` to resume
+    // with `changes_since` after reopening the store.
+    pub fn watch(&mut self, prefix: &str) -> Watcher {
+        self.subscribers.subscribe(prefix)
+    }
+
+    // The sequence number of the last write, 0 for an empty store.
+    pub fn last_seq(&self) -> u64 {
+        self.log.last_seq()
+    }
+
+    // Replay all writes with a sequence number after `seq` from the log.
+    // Fails with `ChangesCompacted` when compaction already discarded some.
+    pub fn changes_since(&self, seq: u64) -> Result<Changes<'_>> {
+        Changes::new(&self.log, seq)
+    }
+
+    // Read up to `limit` records from `position` in the log for replication.
+    // Returns None when compaction discarded the records at the position.
+    pub fn fetch_records(&self, position: Position, limit: usize) -> Result<Option<(Vec<KvsEntry>, Position)>> {
+        replication::read_records(&self.log, position, limit)
+    }
+
+    pub(crate) fn log(&self) -> &Log {
+        &self.log
+    }
+
+    pub fn stats(&self) -> Result<StoreStats> {
+        let live_bytes = self.index.pointers(&self.log)?.iter().map(|lp| lp.len()).sum();
+        StoreStats::collect(&self.log, self.len(), live_bytes, self.index_memory(), self.cache_stats())
+    }
+
+    // Write a consistent copy of the store to `dirname`, which must not
+    // contain a store yet. Restore it with `backup::restore`.
+    pub fn backup_to<P: AsRef<Path>>(&self, dirname: P) -> Result<()> {
+        backup::backup_log(&self.log, dirname.as_ref())
+    }
+
+    // Check the integrity of the log and that every index entry points at a
+    // matching record. Use `verify::verify_dir` for stores that fail to open.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut report = verify::check_log(&self.log)?;
+        for (key, lp) in self.index.dangling(&self.log)? {
+            report.problems.push(Problem::DanglingPointer { key, partition: lp.partition(), offset: lp.offset() });
+        }
+        Ok(report)
+    }
+
+    fn load_index(&mut self) -> Result<()> {
+        self.index.load(&self.log)?;
         // eprintln!("loaded index: {:?}", self.index);
         Ok(())
     }
 
     fn maybe_compact(&mut self) -> Result<()> {
-        if self.log.hist.len() > 2 {
-            let orig_entries = self.log.len();
-            if orig_entries > COMPACTION_FACTOR * self.len() {
-                self.log.compact(self.index.iter())?;
-                println!("Compacted from {} entries to {}", orig_entries, self.log.len());
-            }
-            // rebuild the index
-            self.load_index()?;
+        let (factor, min_partitions) = match self.compaction {
+            CompactionPolicy::Never => return Ok(()),
+            CompactionPolicy::Ratio { factor, min_partitions } => (factor, min_partitions),
+        };
+        if self.log.hist.len() >= min_partitions && self.log.len() > factor * self.len() {
+            self.compact()?;
         }
         Ok(())
     }
 
 }
+
+
+pub struct Iter<'a> {
+    log: &'a Log,
+    pointers: vec::IntoIter<LogPointer>,
+}
+
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pointers.next().map(|lp| {
+            match self.log.retrieve(&lp)? {
+                KvsEntry::Set(key, value) => Ok((key, value)),
+                KvsEntry::Remove(_) => Err(lp.invalid()),
+            }
+        })
+    }
+
+}
@@ -0,0 +1,94 @@
+use std::{
+    self,
+    io::{Read, Write, Seek, SeekFrom, ErrorKind},
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::error::*;
+
+
+fn lock_file_path(dirname: &Path) -> PathBuf {
+    let mut path = PathBuf::from(dirname);
+    path.push("LOCK");
+    path
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    // A single writer, recorded in the lock file by its PID.
+    Exclusive,
+    // Any number of readers and no writer.
+    Shared,
+}
+
+
+// An advisory lock on a store directory, released when dropped. Both modes
+// create the lock file when it is missing, a reader has to hold it before a
+// writer comes along.
+#[derive(Debug)]
+pub struct DirLock {
+    fh: File,
+    mode: LockMode,
+}
+
+
+impl DirLock {
+
+    pub fn acquire(dirname: &Path, mode: LockMode) -> Result<DirLock> {
+        let path = lock_file_path(dirname);
+        let mut fh = match mode {
+            LockMode::Exclusive => OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?,
+            // a reader only needs write access to create the file
+            LockMode::Shared => match File::open(&path) {
+                Ok(fh) => fh,
+                Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?
+                },
+                Err(err) => return Err(KvsError::from(err)),
+            },
+        };
+        let locked = match mode {
+            LockMode::Exclusive => fh.try_lock(),
+            LockMode::Shared => fh.try_lock_shared(),
+        };
+        match locked {
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) => {
+                return Err(KvsError::Locked { pid: DirLock::holder(&mut fh) });
+            },
+            Err(TryLockError::Error(err)) => return Err(KvsError::from(err)),
+        }
+        if mode == LockMode::Exclusive {
+            fh.set_len(0)?;
+            fh.seek(SeekFrom::Start(0))?;
+            write!(fh, "{}", process::id())?;
+            fh.sync_data()?;
+        }
+        Ok(DirLock { fh, mode })
+    }
+
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    // The PID of the process holding the exclusive lock, if it is known.
+    fn holder(fh: &mut File) -> Option<u32> {
+        let mut content = String::new();
+        fh.read_to_string(&mut content).ok()?;
+        content.trim().parse().ok()
+    }
+
+}
+
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // clear the PID before the lock is released by closing the file
+        if self.mode == LockMode::Exclusive {
+            let _ = self.fh.set_len(0);
+        }
+    }
+}
@@ -5,60 +5,74 @@
 use std::{
     self,
     mem,
+    cmp::Ordering,
     io::{Read, Write, Seek, SeekFrom, ErrorKind},
     fs::{self, File, OpenOptions},
-    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    collections::VecDeque,
     path::{Path, PathBuf},
 };
 use time::OffsetDateTime;
 use serde::{
     Serialize,
     Deserialize,
-    de::DeserializeOwned
+    de::{DeserializeOwned, IgnoredAny},
 };
 use serde_json;
 
 use crate::error::*;
-
-
-fn meta_file_path(dirname: &Path) -> PathBuf {
-    let mut path = PathBuf::from(dirname);
-    path.push("logparts");
-    path
-}
+use crate::format::{self, Manifest, PartitionHeader, FORMAT_VERSION, HEADER_LEN};
+use crate::options::{KvStoreOptions, SyncPolicy, DEFAULT_MAX_PARTITION_SIZE};
 
 
 // ~~~~~ Entry ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Entry<K, V> {
     Set(K, V),
     Remove(K),
 }
 
 
+impl<K, V> Entry<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Set(key, _) | Entry::Remove(key) => key,
+        }
+    }
+}
+
+
 // ~~~~~ LogPartition ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LogPartition {
-    entry_count: u16,
+    pub(crate) entry_count: u16,
     file_id: u128,
+    // The sequence number of the first record, the following records are
+    // numbered consecutively. 0 for stores written before the numbering.
+    #[serde(default)]
+    pub(crate) first_seq: u64,
 }
 
 
 impl LogPartition {
 
-    fn new(dirname: &Path) -> Result<(LogPartition, File)> {
-        // TODO: more defensive to limit the number of iterations?
+    // Create a new partition file starting with its header, taking the file
+    // id from the `next_file_id` generation counter. Ids already taken by a
+    // file, e.g. one left behind by a crash, are skipped.
+    pub(crate) fn new(dirname: &Path, next_file_id: &mut u128) -> Result<(LogPartition, File)> {
         loop {
-            let file_id = OffsetDateTime::now_utc().timestamp_nanos() as u128;
+            let file_id = *next_file_id;
+            *next_file_id += 1;
             let name = LogPartition::build_file_name(file_id);
             let mut path = PathBuf::from(dirname);
             path.push(&name);
             let fh = OpenOptions::new().write(true).create_new(true).open(path);
             match fh {
-                Ok(f) => {
-                    return Ok((LogPartition { entry_count: 0, file_id, }, f));
+                Ok(mut f) => {
+                    PartitionHeader::new().write_to(&mut f)?;
+                    return Ok((LogPartition { entry_count: 0, file_id, first_seq: 0 }, f));
                 }
                 Err(err) => {
                     match err.kind() {
@@ -70,15 +84,33 @@ impl LogPartition {
         }
     }
 
-    fn build_file_name(file_id: u128) -> String {
-        format!("{:x}.dblog", file_id)
+    // Zero padded so the file names sort in generation order.
+    pub(crate) fn build_file_name(file_id: u128) -> String {
+        format!("{:016x}.dblog", file_id)
+    }
+
+    pub fn file_id(&self) -> u128 {
+        self.file_id
+    }
+
+    pub fn entry_count(&self) -> u16 {
+        self.entry_count
+    }
+
+    pub fn first_seq(&self) -> u64 {
+        self.first_seq
+    }
+
+    // The sequence number the next record appended to the partition gets.
+    pub fn end_seq(&self) -> u64 {
+        self.first_seq + self.entry_count as u64
     }
 
-    fn file_name(&self) -> String {
+    pub fn file_name(&self) -> String {
         LogPartition::build_file_name(self.file_id)
     }
 
-    fn full_path(&self, dirname: &Path) -> PathBuf {
+    pub fn full_path(&self, dirname: &Path) -> PathBuf {
         let mut path = PathBuf::from(dirname);
         path.push(self.file_name());
         path
@@ -93,29 +125,35 @@ impl LogPartition {
 
 struct LogPartitionIter<'de, I> {
     iter: serde_json::StreamDeserializer<'de, serde_json::de::IoRead<File>, I>,
+    file_id: u128,
 }
 
 
 impl<'de, I: Deserialize<'de>> LogPartitionIter<'de, I> {
 
-    fn new(path: PathBuf) -> LogPartitionIter<'de, I> {
-        let fh = OpenOptions::new().read(true).create(false).open(&path).unwrap();
+    fn new(partition: &LogPartition, dirname: &Path) -> Result<LogPartitionIter<'de, I>> {
+        let mut fh = OpenOptions::new().read(true).create(false).open(partition.full_path(dirname))
+            .map_err(|err| KvsError::from_partition_io(err, partition.file_id))?;
+        PartitionHeader::read_from(&mut fh, partition.file_id)?;
         let deserializer = serde_json::Deserializer::from_reader(fh);
-        LogPartitionIter { iter: deserializer.into_iter::<I>() }
+        Ok(LogPartitionIter { iter: deserializer.into_iter::<I>(), file_id: partition.file_id })
     }
 
+    // The offset in the partition file, the deserializer starts after the header.
     fn current_offset(&self) -> usize {
-        self.iter.byte_offset()
+        HEADER_LEN as usize + self.iter.byte_offset()
     }
 
 }
 
 
 impl<'de, I: Deserialize<'de>> Iterator for LogPartitionIter<'de, I> {
-    type Item = I;
+    type Item = Result<I>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|item| item.unwrap())
+        let offset = self.current_offset() as u64;
+        let file_id = self.file_id;
+        self.iter.next().map(|item| item.map_err(|err| KvsError::from_record(err, file_id, offset)))
     }
 
 }
@@ -150,95 +188,260 @@ impl<'de, I: Deserialize<'de>> Iterator for LogPartitionIter<'de, I> {
 
 // ~~~~~ LogPointer ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
 
-#[derive(Debug)]
+// A LogPointer refers to a partition by its ordinal in the Log (the position
+// in `hist`, with the active partition following the last historic one)
+// rather than by its u128 file id. Ordinals stay valid when the active
+// partition is rotated into `hist` and only change on compaction, after which
+// the index is rebuilt anyway.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct LogPointer {
-    file_id: u128,
+    partition: u32,
+    len: u32,
     offset: u64,
-    len: u64,
 }
 
 
 impl LogPointer {
-    pub fn len(&self) -> u64 { self.len }
+    pub fn new(partition: u32, offset: u64, len: u32) -> LogPointer {
+        LogPointer { partition, len, offset }
+    }
+    pub fn partition(&self) -> u32 { self.partition }
+    pub fn len(&self) -> u64 { self.len as u64 }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
     pub fn offset(&self) -> u64 { self.offset }
+    pub(crate) fn invalid(&self) -> KvsError {
+        KvsError::InvalidLogPointer { partition: self.partition, offset: self.offset }
+    }
+}
+
+
+// ~~~~~ LogConfig ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// The settings persisted with the Log's meta data.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogConfig {
+    pub max_partition_size: u64,
+}
+
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            max_partition_size: DEFAULT_MAX_PARTITION_SIZE,
+        }
+    }
 }
 
 
 // ~~~~~ Log ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
 
-#[derive(Serialize, Deserialize, Debug)]
+// The Log's meta data is persisted in `logparts` as a `format::Manifest`.
+#[derive(Debug)]
 pub struct Log {
     pub dirname: PathBuf,
     pub active: LogPartition,
     pub hist: Vec<LogPartition>,
-    #[serde(skip)]
+    pub config: LogConfig,
+    // Unix timestamp of the last successful compaction.
+    pub last_compaction: Option<i64>,
+    // The file id of the next partition, ids only ever increase.
+    pub next_file_id: u128,
+    // The sequence number of the last record discarded by compaction.
+    pub compacted_seq: u64,
     pub fh: Option<File>,
-    #[serde(skip)]
-    hist_map: HashMap<u128, usize>,
+    read_only: bool,
+    // Set once `close` wrote the meta data.
+    closed: bool,
+    sync: SyncPolicy,
+    unsynced: u32,
+    // Set while compaction copies records into new partitions, which must not
+    // be listed in `logparts` before all of them are written.
+    compacting: bool,
 }
 
 
 impl Log {
 
-    pub fn open(dirname: &Path) -> Result<Log> {
+    pub fn exists(dirname: &Path) -> bool {
+        format::meta_file_path(dirname).exists()
+    }
+
+    pub fn open(dirname: &Path, options: &KvStoreOptions) -> Result<Log> {
         // load the meta data for the log
-        let meta_path = meta_file_path(dirname);
-        match &meta_path.exists() {
+        match Log::exists(dirname) {
             true => {
+                if options.error_if_exists {
+                    return Err(KvsError::StoreExists);
+                }
                 // deserialize the Log struct
-                let fh = OpenOptions::new().read(true).create(false).open(meta_path)?;
-                let mut log: Log = serde_json::from_reader(fh)?;
+                let mut log = Log::read_meta(dirname)?;
+                log.apply_options(options)?;
                 // open the active partition file
-                let path = log.active.full_path(&dirname);
-                log.fh = Some(OpenOptions::new().read(true).append(true).create(false).open(path)?);
-                for (i, p) in log.hist.iter().enumerate() {
-                    log.hist_map.insert(p.file_id, i);
-                }
+                let path = log.active.full_path(dirname);
+                let fh = OpenOptions::new().read(true).append(true).create(false).open(path)
+                    .map_err(|err| KvsError::from_partition_io(err, log.active.file_id))?;
+                log.fh = Some(fh);
+                log.recount_active()?;
                 Ok(log)
             },
             false => {
+                if !options.create_if_missing {
+                    return Err(KvsError::StoreNotFound);
+                }
                 // initialize a new partition
-                let (active_part, fh) = LogPartition::new(dirname)?;
+                let mut next_file_id = 1;
+                let (mut active_part, fh) = LogPartition::new(dirname, &mut next_file_id)?;
+                active_part.first_seq = 1;
                 // initialize the Log struct
                 let log = Log {
                     dirname: PathBuf::from(dirname),
                     active: active_part,
                     hist: vec![],
+                    config: LogConfig {
+                        max_partition_size: options.max_partition_size.unwrap_or(DEFAULT_MAX_PARTITION_SIZE),
+                    },
+                    last_compaction: None,
+                    next_file_id,
+                    compacted_seq: 0,
                     fh: Some(fh),
-                    hist_map: HashMap::new(),
+                    read_only: false,
+                    closed: false,
+                    sync: options.sync,
+                    unsynced: 0,
+                    compacting: false,
                 };
                 // write the Log struct's meta data to disk
-                let fh = OpenOptions::new().write(true).create_new(true).open(meta_path)?;
-                serde_json::to_writer(fh, &log)?;
+                log.dump_meta()?;
                 Ok(log)
             },
         }
     }
 
+    // Open an existing log without ever writing to its directory.
+    pub fn open_read_only(dirname: &Path, options: &KvStoreOptions) -> Result<Log> {
+        if !Log::exists(dirname) {
+            return Err(KvsError::StoreNotFound);
+        }
+        let mut log = Log::read_meta(dirname)?;
+        log.read_only = true;
+        log.apply_options(options)?;
+        Ok(log)
+    }
+
+    fn read_meta(dirname: &Path) -> Result<Log> {
+        let mut manifest = format::read_manifest(dirname)?;
+        // the partitions are replayed in the order of their generation, the
+        // active partition is always the newest
+        manifest.hist.sort_by_key(|p| p.file_id);
+        if manifest.hist.last().is_some_and(|p| p.file_id >= manifest.active.file_id) {
+            return Err(KvsError::CorruptMeta(serde::de::Error::custom(
+                "the active partition is older than a sealed partition"
+            )));
+        }
+        // stores written before the counter existed continue after their newest partition
+        let next_file_id = manifest.next_file_id.max(manifest.active.file_id + 1);
+        // and number their records from the start of the log
+        let mut next_seq = 1;
+        for partition in manifest.hist.iter_mut().chain(Some(&mut manifest.active)) {
+            if partition.first_seq == 0 {
+                partition.first_seq = next_seq;
+            }
+            next_seq = partition.end_seq();
+        }
+        Ok(Log {
+            // the directory may have been moved or restored from a backup
+            dirname: PathBuf::from(dirname),
+            active: manifest.active,
+            hist: manifest.hist,
+            config: manifest.config,
+            last_compaction: manifest.last_compaction,
+            next_file_id,
+            compacted_seq: manifest.compacted_seq,
+            fh: None,
+            read_only: false,
+            closed: false,
+            sync: SyncPolicy::Never,
+            unsynced: 0,
+            compacting: false,
+        })
+    }
+
+    // `logparts` is not written for every append, after a crash the active
+    // partition can hold more records than it lists. A torn record at the end
+    // is left for loading the index to report.
+    fn recount_active(&mut self) -> Result<()> {
+        let records = LogPartitionIter::<IgnoredAny>::new(&self.active, &self.dirname)?
+            .take_while(|record| record.is_ok())
+            .count();
+        self.active.entry_count = u16::try_from(records).unwrap_or(u16::MAX);
+        Ok(())
+    }
+
+    // Take over the settings given in the options.
+    fn apply_options(&mut self, options: &KvStoreOptions) -> Result<()> {
+        if let Some(max_partition_size) = options.max_partition_size {
+            self.config.max_partition_size = max_partition_size;
+        }
+        self.sync = options.sync;
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
     pub fn len(&self) -> usize {
         let mut sum: usize = self.hist.iter().map(|p| p.entry_count as usize).sum();
         sum += self.active.entry_count as usize;
         sum
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn append<K, V>(&mut self, entry: &Entry<K, V>) -> Result<LogPointer>
         where
             K: Sized + Serialize,
             V: Sized + Serialize,
     {
-        if self.active.entry_count == u16::MAX {
-            self.initialize_new_active()?;
-        }
+        let offset = self.prepare_append()?;
         let mut fh = self.fh.as_ref().ok_or(KvsError::InvalidLogFileHandle)?;
-        let offset = fh.seek(SeekFrom::End(0))?;
         serde_json::to_writer(fh, &entry)?;
-        let len = fh.seek(SeekFrom::Current(0))? - offset;
+        let len = fh.stream_position()? - offset;
         self.active.entry_count += 1;
-        Ok(LogPointer {
-            file_id: self.active.file_id,
-            offset,
-            len,
-        })
+        self.maybe_sync()?;
+        self.active_pointer(offset, len)
+    }
+
+    // Append all entries with as few writes and syncs as possible: the entries
+    // are serialized up front and written with one write per partition they
+    // end up in. A crash can still leave only part of the batch on disk.
+    pub fn append_batch<K, V>(&mut self, entries: &[Entry<K, V>]) -> Result<Vec<LogPointer>>
+        where
+            K: Sized + Serialize,
+            V: Sized + Serialize,
+    {
+        let mut pointers = Vec::with_capacity(entries.len());
+        let mut remaining = entries;
+        while !remaining.is_empty() {
+            let offset = self.prepare_append()?;
+            let room = (u16::MAX - self.active.entry_count) as usize;
+            let (chunk, rest) = remaining.split_at(room.min(remaining.len()));
+            let mut buf = vec![];
+            for entry in chunk {
+                let start = buf.len() as u64;
+                serde_json::to_writer(&mut buf, entry)?;
+                pointers.push(self.active_pointer(offset + start, buf.len() as u64 - start)?);
+            }
+            let mut fh = self.fh.as_ref().ok_or(KvsError::InvalidLogFileHandle)?;
+            fh.write_all(&buf)?;
+            self.active.entry_count += chunk.len() as u16;
+            self.unsynced = self.unsynced.saturating_add(chunk.len() as u32 - 1);
+            self.maybe_sync()?;
+            remaining = rest;
+        }
+        Ok(pointers)
     }
 
     pub fn retrieve<K, V>(&self, lp: &LogPointer) -> Result<Entry<K, V>>
@@ -246,44 +449,67 @@ impl Log {
             K: Sized + DeserializeOwned,
             V: Sized + DeserializeOwned,
     {
-        let partition = self.hist_map.get(&lp.file_id).map_or(&self.active, |i| &self.hist[*i]);
-        let mut fh = File::open(partition.full_path(&self.dirname))?;
+        let partition = self.partition(lp.partition).ok_or(lp.invalid())?;
+        let mut fh = File::open(partition.full_path(&self.dirname))
+            .map_err(|err| KvsError::from_partition_io(err, partition.file_id))?;
         fh.seek(SeekFrom::Start(lp.offset))?;
-        let handle = fh.take(lp.len);
-        serde_json::from_reader(handle).map_err(|err| KvsError::from(err))
+        let handle = fh.take(lp.len());
+        serde_json::from_reader(handle).map_err(|err| KvsError::from_record(err, partition.file_id, lp.offset))
     }
 
     pub fn iter<'de, I: Deserialize<'de>>(&'de self) -> LogIter<'de, I> {
-        LogIter::new(self)
+        LogIter::new(self, 0)
+    }
+
+    // Iterate starting at the partition with ordinal `skip`.
+    pub fn iter_from<'de, I: Deserialize<'de>>(&'de self, skip: usize) -> LogIter<'de, I> {
+        LogIter::new(self, skip)
+    }
+
+    // The sequence number of the last appended record, 0 for an empty log.
+    pub fn last_seq(&self) -> u64 {
+        self.active.end_seq() - 1
     }
 
-    pub fn compact<'a, I: Iterator<Item = (&'a K, &'a LogPointer)>, K: 'a>(&mut self, records: I) -> Result<()> {
-        let (compact_active, compact_fh) = LogPartition::new(&self.dirname)?;
+    pub(crate) fn partition(&self, ordinal: u32) -> Option<&LogPartition> {
+        match (ordinal as usize).cmp(&self.hist.len()) {
+            Ordering::Less => Some(&self.hist[ordinal as usize]),
+            Ordering::Equal => Some(&self.active),
+            Ordering::Greater => None,
+        }
+    }
+
+    pub fn compact<I: IntoIterator<Item = LogPointer>>(&mut self, records: I) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let end_seq = self.active.end_seq();
+        let (compact_active, compact_fh) = self.new_partition()?;
         // backup the current state
         let current_active = mem::replace(&mut self.active, compact_active);
-        let current_hist = mem::replace(&mut self.hist, vec![]);
-        let current_hist_map = mem::replace(&mut self.hist_map, HashMap::new());
-        let current_fh = mem::replace(&mut self.fh, Some(compact_fh));
-        let mut result = Ok(());
-        let mut partition = &current_active;
-        let mut fh = File::open(partition.full_path(&self.dirname))?;
-        for (_key, lp) in records {
-            if lp.file_id != partition.file_id {
-                partition = current_hist_map.get(&lp.file_id).map_or(&current_active, |i| &current_hist[*i]);
-                assert_eq!(lp.file_id, partition.file_id);
-                fh = File::open(partition.full_path(&self.dirname))?;
-            }
-            fh.seek(SeekFrom::Start(lp.offset))?;
-            let mut entry = vec![0_u8; lp.len() as usize];
-            fh.read_exact(&mut entry[..])?;
-            assert_eq!(entry.len(), lp.len as usize, "could not read from partition");
-
-            if let Err(err) = self.append_bytes(&entry) {
-                result = Err(err);
-                break;
-            }
+        let current_hist = mem::take(&mut self.hist);
+        let current_fh = self.fh.replace(compact_fh);
+        self.compacting = true;
+        let mut result = self.copy_records(records, &current_hist, &current_active);
+        self.compacting = false;
+        if result.is_ok() && self.sync != SyncPolicy::Never {
+            result = self.sync_active();
         }
         // cleanup
+        let (last_compaction, compacted_seq) = (self.last_compaction, self.compacted_seq);
+        if result.is_ok() {
+            self.last_compaction = Some(OffsetDateTime::now_utc().unix_timestamp());
+            // the copied records are numbered right below the next
+            // sequence number, so they all fall in the compacted range
+            self.compacted_seq = end_seq - 1;
+            let mut next_seq = end_seq;
+            for partition in self.hist.iter_mut().chain(Some(&mut self.active)).rev() {
+                partition.first_seq = next_seq - partition.entry_count as u64;
+                next_seq = partition.first_seq;
+            }
+            // the old partitions are only deleted once `logparts` lists the new ones
+            result = self.dump_meta();
+        }
         match result {
             Ok(_) => {
                 for partition in &current_hist {
@@ -292,6 +518,8 @@ impl Log {
                 fs::remove_file(current_active.full_path(&self.dirname))?;
             },
             Err(_) => {
+                self.last_compaction = last_compaction;
+                self.compacted_seq = compacted_seq;
                 for partition in &self.hist {
                     fs::remove_file(partition.full_path(&self.dirname))?;
                 }
@@ -300,41 +528,168 @@ impl Log {
                 self.active = current_active;
                 self.hist = current_hist;
                 self.fh = current_fh;
-                self.hist_map = current_hist_map;
             },
         }
         result
     }
 
+    // Append the records `records` points at in the partitions `hist` and
+    // `active` to the current active partition.
+    fn copy_records<I>(&mut self, records: I, hist: &[LogPartition], active: &LogPartition) -> Result<()>
+        where I: IntoIterator<Item = LogPointer>,
+    {
+        let mut current: Option<(u32, u128, File)> = None;
+        for lp in records {
+            if current.as_ref().is_none_or(|(partition, _, _)| *partition != lp.partition) {
+                let partition = match (lp.partition as usize).cmp(&hist.len()) {
+                    Ordering::Less => &hist[lp.partition as usize],
+                    Ordering::Equal => active,
+                    Ordering::Greater => return Err(lp.invalid()),
+                };
+                let fh = File::open(partition.full_path(&self.dirname))
+                    .map_err(|err| KvsError::from_partition_io(err, partition.file_id))?;
+                current = Some((lp.partition, partition.file_id, fh));
+            }
+            // the partition file is always set above
+            let (_, file_id, fh) = current.as_mut().ok_or(KvsError::InvalidLogFileHandle)?;
+            fh.seek(SeekFrom::Start(lp.offset))?;
+            let mut entry = vec![0_u8; lp.len() as usize];
+            fh.read_exact(&mut entry[..]).map_err(|err| match err.kind() {
+                ErrorKind::UnexpectedEof => KvsError::Corruption { file_id: *file_id, offset: lp.offset },
+                _ => KvsError::Io(err),
+            })?;
+            self.append_bytes(&entry)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn new_partition(&mut self) -> Result<(LogPartition, File)> {
+        LogPartition::new(&self.dirname, &mut self.next_file_id)
+    }
+
     fn initialize_new_active(&mut self) -> Result<()> {
-        let (active, fh) = LogPartition::new(&self.dirname)?;
-        let i = self.hist.len();
-        self.hist_map.insert(self.active.file_id, i);
+        if self.sync != SyncPolicy::Never {
+            // seal the partition on disk before moving on
+            self.sync_active()?;
+        }
+        let (mut active, fh) = self.new_partition()?;
+        active.first_seq = self.active.end_seq();
         self.hist.push(mem::replace(&mut self.active, active));
         self.fh = Some(fh);
+        // a sealed partition is only found again through `logparts`
+        match self.compacting {
+            true => Ok(()),
+            false => self.dump_meta(),
+        }
+    }
+
+    // Returns the offset the next entry will be written at, rotating the active
+    // partition first when it is full.
+    fn prepare_append(&mut self) -> Result<u64> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let mut fh = self.fh.as_ref().ok_or(KvsError::InvalidLogFileHandle)?;
+        let offset = fh.seek(SeekFrom::End(0))?;
+        let full = self.active.entry_count == u16::MAX
+            || (self.active.entry_count > 0 && offset >= self.config.max_partition_size);
+        if !full {
+            return Ok(offset);
+        }
+        self.initialize_new_active()?;
+        Ok(HEADER_LEN)
+    }
+
+    fn maybe_sync(&mut self) -> Result<()> {
+        self.unsynced += 1;
+        let due = match self.sync {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Every(n) => self.unsynced >= n,
+        };
+        if due {
+            self.sync_active()?;
+        }
+        Ok(())
+    }
+
+    fn sync_active(&mut self) -> Result<()> {
+        if let Some(fh) = &self.fh {
+            fh.sync_data()?;
+        }
+        self.unsynced = 0;
         Ok(())
     }
 
     fn append_bytes(&mut self, entry: &[u8]) -> Result<LogPointer> {
         // eprintln!("Log::append()");
-        if self.active.entry_count == u16::MAX {
-            self.initialize_new_active()?;
-        }
+        let offset = self.prepare_append()?;
         let mut fh = self.fh.as_ref().ok_or(KvsError::InvalidLogFileHandle)?;
-        let offset = fh.seek(SeekFrom::End(0))?;
         fh.write_all(entry)?;
-        let len = fh.seek(SeekFrom::Current(0))? - offset;
+        let len = fh.stream_position()? - offset;
         self.active.entry_count += 1;
-        Ok(LogPointer {
-            file_id: self.active.file_id,
-            offset,
-            len,
-        })
+        self.active_pointer(offset, len)
     }
 
-    fn dump_meta(&mut self) -> Result<()> {
-        let fh = OpenOptions::new().write(true).create(true).open(&meta_file_path(&self.dirname))?;
-        serde_json::to_writer(fh, self)?;
+    fn active_pointer(&self, offset: u64, len: u64) -> Result<LogPointer> {
+        let len = u32::try_from(len).map_err(|_| KvsError::EntryTooLarge)?;
+        Ok(LogPointer::new(self.hist.len() as u32, offset, len))
+    }
+
+    // A read-only copy of the meta data for a log in another directory,
+    // used to write `logparts` for backups.
+    pub(crate) fn copy_meta(&self, dirname: &Path) -> Log {
+        Log {
+            dirname: PathBuf::from(dirname),
+            active: self.active.clone(),
+            hist: self.hist.clone(),
+            config: self.config,
+            last_compaction: self.last_compaction,
+            next_file_id: self.next_file_id,
+            compacted_seq: self.compacted_seq,
+            fh: None,
+            read_only: true,
+            closed: false,
+            sync: SyncPolicy::Never,
+            unsynced: 0,
+            compacting: false,
+        }
+    }
+
+    pub(crate) fn dump_meta(&self) -> Result<()> {
+        self.write_meta(self.sync != SyncPolicy::Never)
+    }
+
+    fn write_meta(&self, sync_dir: bool) -> Result<()> {
+        let manifest = Manifest {
+            format_version: FORMAT_VERSION,
+            config: self.config,
+            last_compaction: self.last_compaction,
+            next_file_id: self.next_file_id,
+            compacted_seq: self.compacted_seq,
+            active: self.active.clone(),
+            hist: self.hist.clone(),
+        };
+        format::write_manifest(&self.dirname, &manifest, sync_dir)
+    }
+
+    // Flush the active partition and write the meta data, everything appended
+    // so far survives a crash.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.sync_active()?;
+        self.write_meta(true)
+    }
+
+    // Write the meta data to disk, returning any error instead of leaving it
+    // to `drop`, which has to ignore them.
+    pub fn close(&mut self) -> Result<()> {
+        if !self.read_only && !self.closed {
+            self.dump_meta()?;
+            self.closed = true;
+        }
         Ok(())
     }
 
@@ -344,7 +699,8 @@ impl Log {
 // Make sure the meta data for the Log is written to disk
 impl Drop for Log {
     fn drop(&mut self) {
-        self.dump_meta().unwrap()
+        // the store can be opened again with `verify --repair` if this fails
+        let _ = self.close();
     }
 }
 
@@ -353,33 +709,41 @@ pub struct LogIter<'de, I> {
     dirname: PathBuf,
     partitions: VecDeque<&'de LogPartition>,
     current_iterator: Option<LogPartitionIter<'de, I>>,
-    current_file_id: u128,
+    current_partition: u32,
 }
 
 
 impl<'de, I: Deserialize<'de>> LogIter<'de, I> {
-    fn new(log: &'de Log) -> LogIter<'de, I> {
-        let mut partitions: VecDeque<&LogPartition> = log.hist.iter().collect();
+    fn new(log: &'de Log, skip: usize) -> LogIter<'de, I> {
+        let skip = skip.min(log.hist.len());
+        let mut partitions: VecDeque<&LogPartition> = log.hist[skip..].iter().collect();
         partitions.push_back(&log.active);
         LogIter {
             dirname: log.dirname.clone(),
             partitions,
             current_iterator: None,
-            current_file_id: 0,
+            current_partition: skip as u32,
         }
     }
 }
 
 
+// Yields an error for a missing partition or the first record that does not
+// decode, after which the iteration stops.
 impl<'de, I: Deserialize<'de>> Iterator for LogIter<'de, I> {
-    type Item = (I, LogPointer);
+    type Item = Result<(I, LogPointer)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.current_iterator.is_none() {
             match self.partitions.pop_front() {
                 Some(partition) => {
-                    self.current_file_id = partition.file_id;
-                    self.current_iterator = Some(LogPartitionIter::new(partition.full_path(&self.dirname)));
+                    match LogPartitionIter::new(partition, &self.dirname) {
+                        Ok(iterator) => self.current_iterator = Some(iterator),
+                        Err(err) => {
+                            self.partitions.clear();
+                            return Some(Err(err));
+                        },
+                    }
                 },
                 None => {
                     return None;
@@ -392,16 +756,17 @@ impl<'de, I: Deserialize<'de>> Iterator for LogIter<'de, I> {
             Some(ref mut it) => {
                 let offset = it.current_offset();
                 match it.next() {
-                    Some(entry) => {
+                    Some(Ok(entry)) => {
                         let len =  it.current_offset() - offset;
                         self.current_iterator = iterator;
-                        Some((entry, LogPointer {
-                            file_id: self.current_file_id,
-                            offset: offset as u64,
-                            len: len as u64,
-                        }))
+                        Some(Ok((entry, LogPointer::new(self.current_partition, offset as u64, len as u32))))
+                    },
+                    Some(Err(err)) => {
+                        self.partitions.clear();
+                        Some(Err(err))
                     },
                     None => {
+                        self.current_partition += 1;
                         self.next()
                     }
                 }
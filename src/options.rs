@@ -0,0 +1,165 @@
+use std::{
+    self,
+    path::Path,
+};
+use crate::error::*;
+use crate::index::IndexMode;
+use crate::KvStore;
+
+
+// Default upper bound, in bytes, for the in-memory value cache.
+pub const DEFAULT_CACHE_CAPACITY: usize = 4 * 1024 * 1024;
+
+// Default size in bytes after which the active partition is rotated.
+pub const DEFAULT_MAX_PARTITION_SIZE: u64 = 64 * 1024 * 1024;
+
+
+// ~~~~~ SyncPolicy ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// When appended entries are flushed to stable storage with fsync. Unless it
+// is Never, a partition is also synced when it is sealed, and `logparts` and
+// the store directory whenever the set of partitions changes, so a crash
+// loses at most the appends since the last sync.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // Leave flushing to the operating system.
+    #[default]
+    Never,
+    // Sync after every append.
+    Always,
+    // Sync after every n appends.
+    Every(u32),
+}
+
+
+// ~~~~~ CompactionPolicy ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionPolicy {
+    Never,
+    // Compact once the log holds at least `min_partitions` sealed partitions
+    // and more than `factor` entries for every live key.
+    Ratio {
+        factor: usize,
+        min_partitions: usize,
+    },
+}
+
+
+impl Default for CompactionPolicy {
+    fn default() -> CompactionPolicy {
+        CompactionPolicy::Ratio { factor: 2, min_partitions: 3 }
+    }
+}
+
+
+// ~~~~~ KvStoreOptions ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Builder for opening a KvStore.
+//
+// The maximum partition size is persisted in the store meta data. When it is
+// left unset an existing store keeps its own, an explicit size replaces it and
+// applies to the partitions rotated from then on.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) create_if_missing: bool,
+    pub(crate) error_if_exists: bool,
+    pub(crate) read_only: bool,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) compaction: CompactionPolicy,
+    pub(crate) max_partition_size: Option<u64>,
+    pub(crate) cache_capacity: usize,
+    pub(crate) index_mode: IndexMode,
+}
+
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            create_if_missing: true,
+            error_if_exists: false,
+            read_only: false,
+            sync: SyncPolicy::default(),
+            compaction: CompactionPolicy::default(),
+            max_partition_size: None,
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            index_mode: IndexMode::default(),
+        }
+    }
+}
+
+
+impl KvStoreOptions {
+
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> KvStoreOptions {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> KvStoreOptions {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn sync(mut self, sync: SyncPolicy) -> KvStoreOptions {
+        self.sync = sync;
+        self
+    }
+
+    pub fn compaction(mut self, compaction: CompactionPolicy) -> KvStoreOptions {
+        self.compaction = compaction;
+        self
+    }
+
+    pub fn max_partition_size(mut self, bytes: u64) -> KvStoreOptions {
+        self.max_partition_size = Some(bytes);
+        self
+    }
+
+    // A capacity of 0 disables the value cache.
+    pub fn cache_capacity(mut self, bytes: usize) -> KvStoreOptions {
+        self.cache_capacity = bytes;
+        self
+    }
+
+    pub fn index_mode(mut self, mode: IndexMode) -> KvStoreOptions {
+        self.index_mode = mode;
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, dirname: P) -> Result<KvStore> {
+        KvStore::open_with_options(dirname, self)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let CompactionPolicy::Ratio { factor, .. } = self.compaction {
+            if factor == 0 {
+                return Err(KvsError::InvalidOptions("the compaction factor must be at least 1".to_owned()));
+            }
+        }
+        if self.max_partition_size == Some(0) {
+            return Err(KvsError::InvalidOptions("the maximum partition size must be positive".to_owned()));
+        }
+        if let SyncPolicy::Every(0) = self.sync {
+            return Err(KvsError::InvalidOptions("the sync interval must be at least 1".to_owned()));
+        }
+        if self.read_only && self.error_if_exists {
+            return Err(KvsError::InvalidOptions("a read-only store has to exist".to_owned()));
+        }
+        if self.read_only && self.index_mode == IndexMode::Spilled {
+            // the spilled index writes its sorted file to the store directory
+            return Err(KvsError::InvalidOptions("a read-only store cannot use a spilled index".to_owned()));
+        }
+        Ok(())
+    }
+
+}
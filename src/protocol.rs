@@ -0,0 +1,119 @@
+use std::net::SocketAddr;
+use serde::{Serialize, Deserialize};
+#[cfg(feature = "async")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::*;
+use crate::log::Entry;
+use crate::raft::{Message, NodeStatus, Reply};
+use crate::replication::Position;
+
+
+// Requests and responses are sent as JSON values, one after the other on the
+// same connection. Every request is answered with exactly one response,
+// except `Snapshot` which streams `SnapshotChunk`s ended by `SnapshotEnd`.
+// A client can send many requests before reading the responses, a request
+// wrapped in `Tagged` is answered with a `Tagged` response carrying its id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Get { key: String },
+    Set { key: String, value: String },
+    Remove { key: String },
+    GetMany { keys: Vec<String> },
+    // Written to the log as one batch.
+    SetMany { pairs: Vec<(String, String)> },
+    // Removes the keys that exist as one batch, answered with the count.
+    RemoveMany { keys: Vec<String> },
+    // At most `limit` pairs with `from <= key < to` in key order.
+    Scan { from: Option<String>, to: Option<String>, limit: usize },
+    // Replication: at most `limit` records from `position` in the primary's log.
+    Fetch { position: Position, limit: usize },
+    // Replication: all live pairs and the position they are current up to.
+    Snapshot,
+    // Cluster: a message from another node.
+    Raft(Message),
+    AddNode { id: u64, addr: SocketAddr },
+    RemoveNode { id: u64 },
+    Status,
+    Tagged { id: u64, request: Box<Request> },
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    Value(Option<String>),
+    Values(Vec<Option<String>>),
+    Count(usize),
+    Pairs(Vec<(String, String)>),
+    KeyNotFound,
+    Err(String),
+    Records { entries: Vec<Entry<String, String>>, next: Position },
+    // The records at the requested position were discarded by compaction.
+    Compacted,
+    SnapshotChunk(Vec<(String, String)>),
+    SnapshotEnd { position: Position },
+    // Cluster: writes go to the leader at the address, when one is known.
+    NotLeader(Option<SocketAddr>),
+    Raft(Reply),
+    Status(NodeStatus),
+    Tagged { id: u64, response: Box<Response> },
+}
+
+
+impl Response {
+
+    pub(crate) fn from_result(result: Result<()>) -> Response {
+        match result {
+            Ok(()) => Response::Ok,
+            Err(err) => Response::from(err),
+        }
+    }
+
+}
+
+
+impl From<KvsError> for Response {
+    fn from(err: KvsError) -> Response {
+        match err {
+            KvsError::KeyNotFound => Response::KeyNotFound,
+            err => Response::Err(err.to_string()),
+        }
+    }
+}
+
+
+// Read the next message from an async connection, None when the peer closed
+// it. `buf` holds what was read past the message and must be passed to the
+// next call.
+#[cfg(feature = "async")]
+pub(crate) async fn read_message<T, R>(reader: &mut R, buf: &mut Vec<u8>) -> Result<Option<T>>
+    where T: DeserializeOwned, R: AsyncRead + Unpin,
+{
+    loop {
+        let mut values = serde_json::Deserializer::from_slice(buf).into_iter::<T>();
+        match values.next() {
+            Some(Ok(value)) => {
+                let len = values.byte_offset();
+                buf.drain(..len);
+                return Ok(Some(value));
+            },
+            Some(Err(err)) if !err.is_eof() => return Err(KvsError::from(err)),
+            _ => (),
+        }
+        // read at least as much as is buffered, a large message is parsed again
+        // only a logarithmic number of times
+        let len = buf.len();
+        buf.resize(len + len.max(8 * 1024), 0);
+        let read = reader.read(&mut buf[len..]).await?;
+        buf.truncate(len + read);
+        if read == 0 {
+            return match buf.iter().all(u8::is_ascii_whitespace) {
+                true => Ok(None),
+                false => Err(KvsError::Protocol("the connection closed in the middle of a message".to_owned())),
+            };
+        }
+    }
+}
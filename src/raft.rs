@@ -0,0 +1,1043 @@
+use std::{
+    self,
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    fmt,
+    fs::{self, File, OpenOptions},
+    hash::{BuildHasher, Hasher},
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+    vec,
+};
+use serde::{Serialize, Deserialize};
+use serde_json;
+
+use crate::client::KvsClient;
+use crate::error::*;
+use crate::protocol::Response;
+use crate::replication;
+use crate::KvStore;
+
+
+// How often the leader sends entries or heartbeats to its followers.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+
+// A follower starts an election when it did not hear from a leader for a
+// random time between this and twice this.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How long a client request waits for its entry to be committed.
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Number of entries per AppendEntries message.
+const APPEND_LIMIT: usize = 1000;
+
+// Number of pairs per InstallSnapshot message.
+const SNAPSHOT_CHUNK: usize = 1000;
+
+// The log is also snapshotted when it grows this long without the store
+// compacting.
+const SNAPSHOT_ENTRIES: usize = 10_000;
+
+
+fn raft_file_path(dirname: &Path, name: &str) -> PathBuf {
+    let mut path = PathBuf::from(dirname);
+    path.push(name);
+    path
+}
+
+
+fn election_deadline() -> Instant {
+    let random = RandomState::new().build_hasher().finish();
+    let jitter = random % ELECTION_TIMEOUT.as_millis() as u64;
+    Instant::now() + ELECTION_TIMEOUT + Duration::from_millis(jitter)
+}
+
+
+// ~~~~~ Messages ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// A node of the cluster, `addr` is where it serves both clients and peers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Member {
+    pub id: u64,
+    pub addr: SocketAddr,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Role::Follower => write!(f, "follower"),
+            Role::Candidate => write!(f, "candidate"),
+            Role::Leader => write!(f, "leader"),
+        }
+    }
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeStatus {
+    pub id: u64,
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<u64>,
+    pub commit: u64,
+    pub applied: u64,
+    pub members: Vec<Member>,
+}
+
+
+// The commands replicated through the log. A `Members` entry changes the
+// cluster as soon as it is appended, one node at a time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Set { key: String, value: String },
+    Remove { key: String },
+    SetMany { pairs: Vec<(String, String)> },
+    RemoveMany { keys: Vec<String> },
+    Members(Vec<Member>),
+    Noop,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RaftEntry {
+    pub index: u64,
+    pub term: u64,
+    pub command: Command,
+}
+
+
+// The messages between nodes. A snapshot is sent as a series of
+// InstallSnapshot messages, the first one replaces the store.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    RequestVote { term: u64, candidate: u64, last_index: u64, last_term: u64 },
+    AppendEntries { term: u64, leader: u64, prev_index: u64, prev_term: u64, entries: Vec<RaftEntry>, commit: u64 },
+    InstallSnapshot {
+        term: u64,
+        leader: u64,
+        last_index: u64,
+        last_term: u64,
+        members: Vec<Member>,
+        pairs: Vec<(String, String)>,
+        first: bool,
+        done: bool,
+    },
+}
+
+
+// On a failed append `match_index` is where the leader should retry from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Vote { term: u64, granted: bool },
+    Append { term: u64, success: bool, match_index: u64 },
+    Snapshot { term: u64 },
+}
+
+
+// ~~~~~ RaftLog ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// The entries after the last snapshot, stored one JSON value per line in
+// `raftlog`. Appends are synced, truncations rewrite the file.
+struct RaftLog {
+    path: PathBuf,
+    fh: BufWriter<File>,
+    entries: Vec<RaftEntry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+}
+
+
+impl RaftLog {
+
+    // A torn entry at the end of the file is dropped.
+    fn open(dirname: &Path, snapshot_index: u64, snapshot_term: u64) -> Result<RaftLog> {
+        let path = raft_file_path(dirname, "raftlog");
+        let mut entries: Vec<RaftEntry> = Vec::new();
+        match File::open(&path) {
+            Ok(fh) => {
+                for line in BufReader::new(fh).lines() {
+                    let entry: RaftEntry = match serde_json::from_str(&line?) {
+                        Ok(entry) => entry,
+                        Err(_) => break,
+                    };
+                    let next = entries.last().map_or(snapshot_index + 1, |last| last.index + 1);
+                    if entry.index == next {
+                        entries.push(entry);
+                    }
+                }
+            },
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(KvsError::from(err)),
+        }
+        let fh = BufWriter::new(OpenOptions::new().append(true).create(true).open(&path)?);
+        let mut log = RaftLog { path, fh, entries, snapshot_index, snapshot_term };
+        log.rewrite()?;
+        Ok(log)
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&RaftEntry> {
+        match index > self.snapshot_index {
+            true => self.entries.get((index - self.snapshot_index - 1) as usize),
+            false => None,
+        }
+    }
+
+    // None for the entries before the snapshot.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index == self.snapshot_index {
+            true => Some(self.snapshot_term),
+            false => self.entry(index).map(|entry| entry.term),
+        }
+    }
+
+    fn entries_from(&self, index: u64, limit: usize) -> Vec<RaftEntry> {
+        let start = (index - self.snapshot_index - 1) as usize;
+        self.entries.iter().skip(start).take(limit).cloned().collect()
+    }
+
+    fn append(&mut self, entries: Vec<RaftEntry>) -> Result<()> {
+        for entry in entries {
+            serde_json::to_writer(&mut self.fh, &entry)?;
+            self.fh.write_all(b"\n")?;
+            self.entries.push(entry);
+        }
+        self.fh.flush()?;
+        self.fh.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    // Remove the entries from `index` on.
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        self.entries.truncate((index - self.snapshot_index - 1) as usize);
+        self.rewrite()
+    }
+
+    // Drop the entries up to `index`, which the snapshot now covers. The
+    // entries after it are kept when the log agrees on the term at `index`.
+    fn compact(&mut self, index: u64, term: u64) -> Result<()> {
+        match self.term_at(index) {
+            Some(found) if found == term && index >= self.snapshot_index => {
+                let covered = (index - self.snapshot_index) as usize;
+                self.entries.drain(..covered);
+            },
+            _ => self.entries.clear(),
+        }
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.rewrite()
+    }
+
+    fn rewrite(&mut self) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for entry in self.entries.iter() {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.fh = BufWriter::new(OpenOptions::new().append(true).open(&self.path)?);
+        Ok(())
+    }
+
+}
+
+
+// ~~~~~ Raft ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// The state that survives a restart, stored in `raftstate`. The store holds
+// the state machine up to `applied`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Meta {
+    term: u64,
+    voted_for: Option<u64>,
+    applied: u64,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_members: Vec<Member>,
+    // Set while a received snapshot is copied into the store.
+    #[serde(default)]
+    staged: Option<StagedSnapshot>,
+}
+
+
+// A snapshot received completely into `raftsnapshot`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StagedSnapshot {
+    index: u64,
+    term: u64,
+    members: Vec<Member>,
+}
+
+
+impl Meta {
+
+    fn load(dirname: &Path) -> Result<Option<Meta>> {
+        match File::open(raft_file_path(dirname, "raftstate")) {
+            Ok(fh) => Ok(Some(serde_json::from_reader(BufReader::new(fh))?)),
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(KvsError::from(err)),
+        }
+    }
+
+    fn save(&self, dirname: &Path) -> Result<()> {
+        let path = raft_file_path(dirname, "raftstate");
+        let tmp_path = path.with_extension("tmp");
+        let fh = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+        serde_json::to_writer(&fh, self)?;
+        fh.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+}
+
+
+struct State {
+    role: Role,
+    term: u64,
+    voted_for: Option<u64>,
+    leader: Option<u64>,
+    log: RaftLog,
+    // the members as of the snapshot and as of the last entry
+    snapshot_members: Vec<Member>,
+    members: Vec<Member>,
+    commit: u64,
+    applied: u64,
+    election_deadline: Instant,
+    heard_from_leader: Option<Instant>,
+    votes: HashSet<u64>,
+    // leader only: replication progress and the results of applied proposals
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    replicators: HashSet<u64>,
+    results: HashMap<u64, Response>,
+    // the last index of the snapshot being received and the store it is
+    // received into, the node's own store is only replaced once it is complete
+    installing: Option<(u64, KvStore)>,
+    compacted_seq: u64,
+}
+
+
+impl State {
+
+    fn meta(&self) -> Meta {
+        Meta {
+            term: self.term,
+            voted_for: self.voted_for,
+            applied: self.applied,
+            snapshot_index: self.log.snapshot_index,
+            snapshot_term: self.log.snapshot_term,
+            snapshot_members: self.snapshot_members.clone(),
+            staged: None,
+        }
+    }
+
+    // The members as of the latest `Members` entry up to `index`.
+    fn members_at(&self, index: u64) -> Vec<Member> {
+        self.log.entries.iter().rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match entry.command {
+                Command::Members(ref members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot_members.clone())
+    }
+
+    // Whether a `Members` entry is not yet committed.
+    fn changing_members(&self) -> bool {
+        self.log.entries.iter()
+            .any(|entry| entry.index > self.commit && matches!(entry.command, Command::Members(_)))
+    }
+
+    fn is_member(&self, id: u64) -> bool {
+        self.members.iter().any(|member| member.id == id)
+    }
+
+    fn has_quorum<F: Fn(u64) -> bool>(&self, f: F) -> bool {
+        let count = self.members.iter().filter(|member| f(member.id)).count();
+        count > self.members.len() / 2
+    }
+
+    fn leader_addr(&self) -> Option<SocketAddr> {
+        let leader = self.leader?;
+        self.members.iter().chain(self.snapshot_members.iter())
+            .find(|member| member.id == leader)
+            .map(|member| member.addr)
+    }
+
+}
+
+
+// One round of replication to a peer, with the term and last index it was
+// prepared for.
+struct Round {
+    addr: SocketAddr,
+    term: u64,
+    last_index: u64,
+    payload: Payload,
+}
+
+
+// A snapshot is read from the store one chunk at a time while it is sent.
+// The store may have applied entries after `last_index` by then, which the
+// peer applies a second time with the same result.
+enum Payload {
+    Append(Option<Message>),
+    Snapshot { last_term: u64, members: Vec<Member>, keys: Option<vec::IntoIter<String>> },
+}
+
+
+// A node of a Raft cluster. Committed entries are applied to the store, which
+// doubles as the snapshot: the log is snapshotted when the store compacts and
+// lagging followers are sent the store's pairs.
+pub(crate) struct Raft {
+    id: u64,
+    dirname: PathBuf,
+    store: Arc<Mutex<KvStore>>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+
+impl Raft {
+
+    // Start the node with the persisted state, or with `members` for a new
+    // cluster. A node started without members waits to be added to a cluster.
+    pub fn start(store: Arc<Mutex<KvStore>>, id: u64, members: Vec<Member>) -> Result<Arc<Raft>> {
+        let (dirname, compacted_seq) = {
+            let store = store.lock().map_err(|_| KvsError::Poisoned)?;
+            (store.path().to_owned(), store.log().compacted_seq)
+        };
+        let meta = match Meta::load(&dirname)? {
+            Some(meta) => meta,
+            None => {
+                let meta = Meta { snapshot_members: members, ..Meta::default() };
+                meta.save(&dirname)?;
+                meta
+            },
+        };
+        let log = RaftLog::open(&dirname, meta.snapshot_index, meta.snapshot_term)?;
+        let mut state = State {
+            role: Role::Follower,
+            term: meta.term,
+            voted_for: meta.voted_for,
+            leader: None,
+            log,
+            snapshot_members: meta.snapshot_members,
+            members: Vec::new(),
+            commit: meta.applied,
+            applied: meta.applied,
+            election_deadline: election_deadline(),
+            heard_from_leader: None,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            replicators: HashSet::new(),
+            results: HashMap::new(),
+            installing: None,
+            compacted_seq,
+        };
+        state.members = state.members_at(state.log.last_index());
+        let staging = raft_file_path(&dirname, "raftsnapshot");
+        let raft = Arc::new(Raft { id, dirname, store, state: Mutex::new(state), changed: Condvar::new() });
+        // finish copying a snapshot the node received before it stopped,
+        // otherwise a partly received one is thrown away
+        match meta.staged {
+            Some(staged) => raft.install(&mut *raft.lock()?, KvStore::open(&staging)?, staged)?,
+            None if staging.exists() => fs::remove_dir_all(&staging)?,
+            None => (),
+        }
+        let ticker = Arc::clone(&raft);
+        thread::spawn(move || loop {
+            thread::sleep(TICK_INTERVAL);
+            if let Err(err) = ticker.tick() {
+                eprintln!("raft node {} failed: {}", ticker.id, err);
+            }
+        });
+        Ok(raft)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, State>> {
+        self.state.lock().map_err(|_| KvsError::Poisoned)
+    }
+
+    pub fn status(&self) -> Result<NodeStatus> {
+        let state = self.lock()?;
+        Ok(NodeStatus {
+            id: self.id,
+            role: state.role,
+            term: state.term,
+            leader: state.leader,
+            commit: state.commit,
+            applied: state.applied,
+            members: state.members.clone(),
+        })
+    }
+
+    fn tick(self: &Arc<Self>) -> Result<()> {
+        let mut state = self.lock()?;
+        match state.role {
+            Role::Leader => self.spawn_replicators(&mut state),
+            _ if Instant::now() >= state.election_deadline && state.is_member(self.id) => {
+                self.start_election(&mut state)?;
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // ~~~~~ Elections ~~~~~
+
+    fn start_election(self: &Arc<Self>, state: &mut State) -> Result<()> {
+        state.term += 1;
+        state.role = Role::Candidate;
+        state.voted_for = Some(self.id);
+        state.leader = None;
+        state.votes = Some(self.id).into_iter().collect();
+        state.election_deadline = election_deadline();
+        state.meta().save(&self.dirname)?;
+        if state.has_quorum(|id| state.votes.contains(&id)) {
+            return self.become_leader(state);
+        }
+        let message = Message::RequestVote {
+            term: state.term,
+            candidate: self.id,
+            last_index: state.log.last_index(),
+            last_term: state.log.last_term(),
+        };
+        for peer in state.members.iter().filter(|member| member.id != self.id) {
+            let (raft, peer, message) = (Arc::clone(self), *peer, message.clone());
+            thread::spawn(move || {
+                if let Ok(reply) = send(peer.addr, message) {
+                    if let Err(err) = raft.count_vote(peer.id, reply) {
+                        eprintln!("raft node {} failed: {}", raft.id, err);
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn count_vote(self: &Arc<Self>, peer: u64, reply: Reply) -> Result<()> {
+        let mut state = self.lock()?;
+        if let Reply::Vote { term, granted } = reply {
+            if term > state.term {
+                return self.step_down(&mut state, term);
+            }
+            if granted && term == state.term && state.role == Role::Candidate {
+                state.votes.insert(peer);
+                if state.has_quorum(|id| state.votes.contains(&id)) {
+                    self.become_leader(&mut state)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn become_leader(self: &Arc<Self>, state: &mut State) -> Result<()> {
+        state.role = Role::Leader;
+        state.leader = Some(self.id);
+        state.next_index.clear();
+        state.match_index.clear();
+        // committing an entry of the new term commits the entries before it
+        self.append(state, Command::Noop)?;
+        self.spawn_replicators(state);
+        self.advance_commit(state)
+    }
+
+    // Follow the leader of `term`, or of the current term when it is not newer.
+    fn step_down(&self, state: &mut State, term: u64) -> Result<()> {
+        if term > state.term {
+            state.term = term;
+            state.voted_for = None;
+            state.meta().save(&self.dirname)?;
+        }
+        if state.role == Role::Leader {
+            state.results.clear();
+        }
+        state.role = Role::Follower;
+        state.election_deadline = election_deadline();
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    // ~~~~~ Replication ~~~~~
+
+    fn append(&self, state: &mut State, command: Command) -> Result<u64> {
+        let index = state.log.last_index() + 1;
+        if let Command::Members(ref members) = command {
+            state.members = members.clone();
+        }
+        state.log.append(vec![RaftEntry { index, term: state.term, command }])?;
+        Ok(index)
+    }
+
+    fn spawn_replicators(self: &Arc<Self>, state: &mut State) {
+        let next_index = state.log.last_index() + 1;
+        for peer in state.members.iter().filter(|member| member.id != self.id) {
+            if state.replicators.insert(peer.id) {
+                state.next_index.entry(peer.id).or_insert(next_index);
+                let (raft, peer) = (Arc::clone(self), peer.id);
+                thread::spawn(move || raft.replicate(peer));
+            }
+        }
+    }
+
+    // Keep `peer` up to date while this node is the leader and `peer` a member.
+    fn replicate(&self, peer: u64) {
+        let mut client: Option<KvsClient> = None;
+        loop {
+            let mut round = match self.lock().and_then(|mut state| self.prepare(&mut state, peer)) {
+                Ok(Some(round)) => round,
+                Ok(None) => return,
+                Err(err) => {
+                    eprintln!("raft node {} failed: {}", self.id, err);
+                    thread::sleep(HEARTBEAT_INTERVAL);
+                    continue;
+                },
+            };
+            let (mut replies, mut done) = (Vec::new(), false);
+            loop {
+                let message = match self.next_message(&mut round) {
+                    Ok(Some(message)) => message,
+                    Ok(None) => {
+                        done = true;
+                        break;
+                    },
+                    Err(err) => {
+                        eprintln!("raft node {} failed: {}", self.id, err);
+                        break;
+                    },
+                };
+                let reply = match client {
+                    Some(ref mut client) => client.raft(message),
+                    None => KvsClient::connect_timeout(round.addr, RPC_TIMEOUT)
+                        .and_then(|new| client.get_or_insert(new).raft(message)),
+                };
+                match reply {
+                    Ok(reply) => replies.push(reply),
+                    Err(_) => {
+                        client = None;
+                        break;
+                    },
+                }
+            }
+            let result = self.lock().and_then(|mut state| {
+                for reply in replies {
+                    self.handle_reply(&mut state, peer, &round, reply, done)?;
+                }
+                // wait for new entries unless the peer is behind
+                let behind = state.next_index.get(&peer).is_some_and(|next| *next <= state.log.last_index());
+                if !behind || client.is_none() {
+                    let _ = self.changed.wait_timeout(state, HEARTBEAT_INTERVAL);
+                }
+                Ok(())
+            });
+            if let Err(err) = result {
+                eprintln!("raft node {} failed: {}", self.id, err);
+            }
+        }
+    }
+
+    // None when the replicator for `peer` should stop.
+    fn prepare(&self, state: &mut State, peer: u64) -> Result<Option<Round>> {
+        let addr = match state.members.iter().find(|member| member.id == peer) {
+            Some(member) if state.role == Role::Leader => member.addr,
+            _ => {
+                state.replicators.remove(&peer);
+                return Ok(None);
+            },
+        };
+        let next = state.next_index.get(&peer).copied().unwrap_or(1);
+        if next > state.log.snapshot_index {
+            let prev_index = next - 1;
+            let entries = state.log.entries_from(next, APPEND_LIMIT);
+            let last_index = prev_index + entries.len() as u64;
+            let message = Message::AppendEntries {
+                term: state.term,
+                leader: self.id,
+                prev_index,
+                prev_term: state.log.term_at(prev_index).unwrap_or(0),
+                entries,
+                commit: state.commit,
+            };
+            let payload = Payload::Append(Some(message));
+            return Ok(Some(Round { addr, term: state.term, last_index, payload }));
+        }
+        // the peer needs entries that were snapshotted, send the store instead
+        let last_index = state.applied;
+        let payload = Payload::Snapshot {
+            last_term: state.log.term_at(last_index).unwrap_or(0),
+            members: state.members_at(last_index),
+            keys: None,
+        };
+        Ok(Some(Round { addr, term: state.term, last_index, payload }))
+    }
+
+    // The next message of `round`, None once all of them were sent. Only the
+    // store is locked while a chunk of the snapshot is read.
+    fn next_message(&self, round: &mut Round) -> Result<Option<Message>> {
+        let (last_term, members, keys) = match round.payload {
+            Payload::Append(ref mut message) => return Ok(message.take()),
+            Payload::Snapshot { last_term, ref members, ref mut keys } => (last_term, members, keys),
+        };
+        let first = keys.is_none();
+        let store = self.store.lock().map_err(|_| KvsError::Poisoned)?;
+        let keys = match keys {
+            Some(keys) if keys.as_slice().is_empty() => return Ok(None),
+            Some(keys) => keys,
+            None => keys.insert(store.keys()?.into_iter()),
+        };
+        let mut pairs = Vec::with_capacity(SNAPSHOT_CHUNK);
+        for key in keys.by_ref().take(SNAPSHOT_CHUNK) {
+            // a key removed since the keys were listed is left out
+            if let Some(value) = store.read(&key)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(Some(Message::InstallSnapshot {
+            term: round.term,
+            leader: self.id,
+            last_index: round.last_index,
+            last_term,
+            members: members.clone(),
+            pairs,
+            first,
+            done: keys.as_slice().is_empty(),
+        }))
+    }
+
+    fn handle_reply(&self, state: &mut State, peer: u64, round: &Round, reply: Reply, done: bool) -> Result<()> {
+        let (term, progress) = match reply {
+            Reply::Vote { term, .. } => (term, None),
+            Reply::Append { term, success: true, match_index } => (term, Some(match_index)),
+            Reply::Append { term, success: false, match_index } => {
+                if term == state.term && state.role == Role::Leader {
+                    state.next_index.insert(peer, match_index + 1);
+                }
+                (term, None)
+            },
+            Reply::Snapshot { term } => (term, match done {
+                true => Some(round.last_index),
+                false => None,
+            }),
+        };
+        if term > state.term {
+            return self.step_down(state, term);
+        }
+        if let Some(match_index) = progress {
+            if term == round.term && state.term == round.term && state.role == Role::Leader {
+                let matched = state.match_index.entry(peer).or_insert(0);
+                *matched = (*matched).max(match_index);
+                let next = *matched + 1;
+                state.next_index.insert(peer, next);
+                self.advance_commit(state)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Commit the last entry of the current term that a majority stores.
+    fn advance_commit(&self, state: &mut State) -> Result<()> {
+        let last_index = state.log.last_index();
+        for index in (state.commit + 1..=last_index).rev() {
+            if state.log.term_at(index) != Some(state.term) {
+                break;
+            }
+            let stored = |id: u64| match id == self.id {
+                true => true,
+                false => state.match_index.get(&id).is_some_and(|matched| *matched >= index),
+            };
+            if state.has_quorum(stored) {
+                state.commit = index;
+                break;
+            }
+        }
+        self.apply(state)
+    }
+
+    // Apply the committed entries to the store and snapshot the log when the
+    // store compacted.
+    fn apply(&self, state: &mut State) -> Result<()> {
+        if state.applied >= state.commit {
+            return Ok(());
+        }
+        let mut removed = false;
+        {
+            let mut store = self.store.lock().map_err(|_| KvsError::Poisoned)?;
+            while state.applied < state.commit {
+                let entry = match state.log.entry(state.applied + 1) {
+                    Some(entry) => entry.clone(),
+                    None => break,
+                };
+                let response = match entry.command {
+                    Command::Set { key, value } => Response::from_result(store.set(key, value)),
+                    Command::Remove { key } => Response::from_result(store.remove(key)),
+                    Command::SetMany { pairs } => Response::from_result(store.set_many(pairs)),
+                    Command::RemoveMany { keys } => match store.remove_many(keys) {
+                        Ok(count) => Response::Count(count),
+                        Err(err) => Response::from(err),
+                    },
+                    Command::Members(ref members) => {
+                        removed = !members.iter().any(|member| member.id == self.id);
+                        Response::Ok
+                    },
+                    Command::Noop => Response::Ok,
+                };
+                state.applied = entry.index;
+                if state.role == Role::Leader && entry.term == state.term {
+                    state.results.insert(entry.index, response);
+                }
+            }
+            // the store must hold what `applied` claims before it is saved,
+            // and what the raftlog discards before it is compacted
+            store.sync()?;
+            let compacted = store.log().compacted_seq != state.compacted_seq;
+            if compacted || state.log.entries.len() > SNAPSHOT_ENTRIES {
+                if !compacted {
+                    store.compact()?;
+                    store.sync()?;
+                }
+                state.compacted_seq = store.log().compacted_seq;
+                let term = state.log.term_at(state.applied).unwrap_or(0);
+                state.snapshot_members = state.members_at(state.applied);
+                state.log.compact(state.applied, term)?;
+            }
+        }
+        state.meta().save(&self.dirname)?;
+        // a leader that removed itself hands over once the removal is committed
+        if removed && state.role == Role::Leader {
+            state.leader = None;
+            self.step_down(state, state.term)?;
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    // ~~~~~ Requests ~~~~~
+
+    pub fn handle(&self, message: Message) -> Result<Reply> {
+        let mut state = self.lock()?;
+        match message {
+            Message::RequestVote { term, candidate, last_index, last_term } => {
+                // a node that hears from a leader ignores candidates, so a
+                // removed or partitioned node cannot disrupt the cluster
+                let leader_alive = state.role == Role::Leader
+                    || state.heard_from_leader.is_some_and(|heard| heard.elapsed() < ELECTION_TIMEOUT);
+                if leader_alive && term > state.term {
+                    return Ok(Reply::Vote { term: state.term, granted: false });
+                }
+                if term > state.term {
+                    self.step_down(&mut state, term)?;
+                }
+                let up_to_date = (last_term, last_index) >= (state.log.last_term(), state.log.last_index());
+                let granted = term == state.term
+                    && up_to_date
+                    && state.voted_for.is_none_or(|voted_for| voted_for == candidate);
+                if granted {
+                    state.voted_for = Some(candidate);
+                    state.election_deadline = election_deadline();
+                    state.meta().save(&self.dirname)?;
+                }
+                Ok(Reply::Vote { term: state.term, granted })
+            },
+            Message::AppendEntries { term, leader, prev_index, prev_term, entries, commit } => {
+                if !self.follow(&mut state, term, leader)? {
+                    return Ok(Reply::Append { term: state.term, success: false, match_index: 0 });
+                }
+                let last_index = state.log.last_index();
+                if prev_index > last_index {
+                    return Ok(Reply::Append { term, success: false, match_index: last_index.min(state.commit) });
+                }
+                match state.log.term_at(prev_index) {
+                    Some(found) if found != prev_term => {
+                        return Ok(Reply::Append { term, success: false, match_index: state.commit });
+                    },
+                    _ => (),
+                }
+                let match_index = prev_index + entries.len() as u64;
+                let mut new = Vec::new();
+                for entry in entries {
+                    if entry.index <= state.log.snapshot_index {
+                        continue;
+                    }
+                    match state.log.term_at(entry.index) {
+                        Some(found) if found == entry.term => continue,
+                        Some(_) => {
+                            state.log.truncate(entry.index)?;
+                            new.push(entry);
+                        },
+                        None => new.push(entry),
+                    }
+                }
+                if !new.is_empty() {
+                    state.log.append(new)?;
+                    state.members = state.members_at(state.log.last_index());
+                }
+                state.commit = state.commit.max(commit.min(match_index));
+                self.apply(&mut state)?;
+                Ok(Reply::Append { term, success: true, match_index })
+            },
+            Message::InstallSnapshot { term, leader, last_index, last_term, members, pairs, first, done } => {
+                if !self.follow(&mut state, term, leader)? {
+                    return Ok(Reply::Snapshot { term: state.term });
+                }
+                if first {
+                    state.installing = None;
+                    if last_index > state.applied {
+                        let path = raft_file_path(&self.dirname, "raftsnapshot");
+                        if path.exists() {
+                            fs::remove_dir_all(&path)?;
+                        }
+                        state.installing = Some((last_index, KvStore::open(&path)?));
+                    }
+                }
+                match state.installing {
+                    Some((index, ref mut staging)) if index == last_index => staging.set_many(pairs)?,
+                    _ => return Ok(Reply::Snapshot { term }),
+                }
+                if let Some((_, mut staging)) = state.installing.take_if(|_| done) {
+                    staging.sync()?;
+                    let staged = StagedSnapshot { index: last_index, term: last_term, members };
+                    Meta { staged: Some(staged.clone()), ..state.meta() }.save(&self.dirname)?;
+                    self.install(&mut state, staging, staged)?;
+                    self.apply(&mut state)?;
+                }
+                Ok(Reply::Snapshot { term })
+            },
+        }
+    }
+
+    // Replace the store with a completely received snapshot. Readers wait for
+    // the store while it is replaced, a node that stops halfway does it again
+    // when it starts.
+    fn install(&self, state: &mut State, staging: KvStore, staged: StagedSnapshot) -> Result<()> {
+        let path = staging.path().to_owned();
+        {
+            let mut store = self.store.lock().map_err(|_| KvsError::Poisoned)?;
+            replication::replace(&mut store, &staging)?;
+            store.sync()?;
+            state.compacted_seq = store.log().compacted_seq;
+        }
+        staging.close()?;
+        state.log.compact(staged.index, staged.term)?;
+        state.snapshot_members = staged.members;
+        state.members = state.members_at(state.log.last_index());
+        state.commit = state.commit.max(staged.index);
+        state.applied = staged.index;
+        state.meta().save(&self.dirname)?;
+        fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    // Accept `leader` for `term`, false when the term is outdated.
+    fn follow(&self, state: &mut State, term: u64, leader: u64) -> Result<bool> {
+        if term < state.term {
+            return Ok(false);
+        }
+        if term > state.term || state.role != Role::Follower {
+            self.step_down(state, term)?;
+        }
+        state.leader = Some(leader);
+        state.heard_from_leader = Some(Instant::now());
+        state.election_deadline = election_deadline();
+        Ok(true)
+    }
+
+    // Append `command` when this node is the leader and wait until it is
+    // applied, otherwise redirect the client to the leader.
+    pub fn propose(&self, command: Command) -> Result<Response> {
+        self.propose_with(|_| Ok(command))
+    }
+
+    pub fn add_member(&self, member: Member) -> Result<Response> {
+        self.propose_with(|state| {
+            if state.members.iter().any(|m| m.id == member.id) {
+                return Err(Response::Err(format!("node {} is already a member", member.id)));
+            }
+            let mut members = state.members.clone();
+            members.push(member);
+            Ok(Command::Members(members))
+        })
+    }
+
+    pub fn remove_member(&self, id: u64) -> Result<Response> {
+        self.propose_with(|state| {
+            if !state.is_member(id) {
+                return Err(Response::Err(format!("node {} is not a member", id)));
+            }
+            let members = state.members.iter().filter(|m| m.id != id).cloned().collect();
+            Ok(Command::Members(members))
+        })
+    }
+
+    fn propose_with<F>(&self, f: F) -> Result<Response>
+        where F: FnOnce(&State) -> std::result::Result<Command, Response>,
+    {
+        let mut state = self.lock()?;
+        if state.role != Role::Leader {
+            return Ok(Response::NotLeader(state.leader_addr()));
+        }
+        let command = match f(&state) {
+            Ok(command) => command,
+            Err(response) => return Ok(response),
+        };
+        if let Command::Members(_) = command {
+            if state.changing_members() {
+                return Ok(Response::Err("a membership change is in progress".to_owned()));
+            }
+        }
+        let term = state.term;
+        let index = self.append(&mut state, command)?;
+        // a cluster of one commits at once
+        self.advance_commit(&mut state)?;
+        self.changed.notify_all();
+        let deadline = Instant::now() + PROPOSAL_TIMEOUT;
+        loop {
+            if state.applied >= index {
+                return Ok(state.results.remove(&index).unwrap_or(Response::Ok));
+            }
+            if state.term != term || state.role != Role::Leader {
+                return Ok(Response::NotLeader(state.leader_addr()));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(Response::Err("timed out waiting for the write to commit".to_owned()));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).map_err(|_| KvsError::Poisoned)?.0;
+        }
+    }
+
+}
+
+
+fn send(addr: SocketAddr, message: Message) -> Result<Reply> {
+    KvsClient::connect_timeout(addr, RPC_TIMEOUT)?.raft(message)
+}
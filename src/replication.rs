@@ -0,0 +1,272 @@
+use std::{
+    self,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Seek, SeekFrom},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use serde::{Serialize, Deserialize};
+use serde_json;
+
+use crate::batch::WriteBatch;
+use crate::client::KvsClient;
+use crate::error::*;
+use crate::format::HEADER_LEN;
+use crate::log::{Entry, Log};
+use crate::KvStore;
+
+
+type KvsEntry = Entry<String, String>;
+
+// A run of records and the position right after the last one.
+pub(crate) type Records = (Vec<KvsEntry>, Position);
+
+// Number of records a follower asks for per fetch.
+const FETCH_LIMIT: usize = 1000;
+
+// How long a follower waits before polling an idle primary again.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// How long a follower waits before reconnecting to an unreachable primary.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Number of pairs per write when a store is replaced by a snapshot.
+const SNAPSHOT_CHUNK: usize = 1000;
+
+
+fn replica_file_path(dirname: &Path) -> PathBuf {
+    let mut path = PathBuf::from(dirname);
+    path.push("replica");
+    path
+}
+
+
+// Where a follower receives a snapshot before it replaces its store.
+fn staging_dir_path(dirname: &Path) -> PathBuf {
+    let mut path = PathBuf::from(dirname);
+    path.push("replicasnapshot");
+    path
+}
+
+
+// ~~~~~ Position ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// A position in the primary's log: the offset of the next record in the
+// partition with `file_id`. The default position is the start of the log.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub file_id: u128,
+    pub offset: u64,
+}
+
+
+impl Position {
+
+    pub fn is_start(&self) -> bool {
+        self.file_id == 0
+    }
+
+    // The position a follower continues from, stored in its `replica` file.
+    pub fn load(dirname: &Path) -> Result<Position> {
+        match File::open(replica_file_path(dirname)) {
+            Ok(fh) => Ok(serde_json::from_reader(BufReader::new(fh))?),
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Position::default()),
+            Err(err) => Err(KvsError::from(err)),
+        }
+    }
+
+    pub fn save(&self, dirname: &Path) -> Result<()> {
+        let path = replica_file_path(dirname);
+        let tmp_path = path.with_extension("tmp");
+        let fh = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+        serde_json::to_writer(&fh, self)?;
+        fh.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+}
+
+
+// Read up to `limit` records starting at `from`, moving on to the next
+// partition at the end of a sealed one. Returns None when the partition of
+// `from` no longer exists because it was compacted.
+pub(crate) fn read_records(log: &Log, from: Position, limit: usize) -> Result<Option<Records>> {
+    let partitions: Vec<_> = log.hist.iter().chain(Some(&log.active)).collect();
+    let (mut index, mut offset) = match from.is_start() {
+        true => (0, HEADER_LEN),
+        false => match partitions.iter().position(|p| p.file_id() == from.file_id) {
+            Some(index) => (index, from.offset.max(HEADER_LEN)),
+            None => return Ok(None),
+        },
+    };
+    let mut entries = Vec::new();
+    loop {
+        let partition = partitions[index];
+        let mut fh = File::open(partition.full_path(&log.dirname))
+            .map_err(|err| KvsError::from_partition_io(err, partition.file_id()))?;
+        fh.seek(SeekFrom::Start(offset))?;
+        let mut records = serde_json::Deserializer::from_reader(BufReader::new(fh)).into_iter::<KvsEntry>();
+        while entries.len() < limit {
+            let record_offset = offset + records.byte_offset() as u64;
+            match records.next() {
+                Some(Ok(entry)) => entries.push(entry),
+                Some(Err(err)) => return Err(KvsError::from_record(err, partition.file_id(), record_offset)),
+                None => break,
+            }
+        }
+        offset += records.byte_offset() as u64;
+        if entries.len() == limit || index + 1 == partitions.len() {
+            return Ok(Some((entries, Position { file_id: partition.file_id(), offset })));
+        }
+        index += 1;
+        offset = HEADER_LEN;
+    }
+}
+
+
+// The position right after the last record in the log.
+pub(crate) fn end_position(log: &Log) -> Result<Position> {
+    let len = fs::metadata(log.active.full_path(&log.dirname))?.len();
+    Ok(Position { file_id: log.active.file_id(), offset: len })
+}
+
+
+// Apply replicated records. Removes of keys the follower does not have are
+// skipped, so records can safely be applied a second time after a crash.
+fn apply(store: &mut KvStore, entries: Vec<KvsEntry>) -> Result<()> {
+    let mut batch = WriteBatch::new();
+    let mut exists: HashMap<String, bool> = HashMap::new();
+    for entry in entries {
+        match entry {
+            Entry::Set(key, value) => {
+                exists.insert(key.clone(), true);
+                batch.set(key, value);
+            },
+            Entry::Remove(key) => {
+                let found = match exists.get(&key) {
+                    Some(found) => *found,
+                    None => store.contains_key(&key)?,
+                };
+                if found {
+                    batch.remove(key.clone());
+                }
+                exists.insert(key, false);
+            },
+        }
+    }
+    store.write(batch)
+}
+
+
+// Make `store` hold the same pairs as `snapshot`, removing the keys the
+// snapshot does not have and writing the pairs in chunks. Nothing is synced.
+pub(crate) fn replace(store: &mut KvStore, snapshot: &KvStore) -> Result<()> {
+    let mut stale = WriteBatch::new();
+    for key in store.keys()? {
+        if !snapshot.contains_key(&key)? {
+            stale.remove(key);
+        }
+    }
+    if !stale.is_empty() {
+        store.write(stale)?;
+    }
+    let mut pairs = snapshot.iter()?;
+    loop {
+        let chunk = pairs.by_ref().take(SNAPSHOT_CHUNK).collect::<Result<Vec<_>>>()?;
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        store.set_many(chunk)?;
+    }
+}
+
+
+// ~~~~~ Follower ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Keeps a store in sync with a primary by fetching the records appended to
+// its log. When the primary compacted past the follower's position the store
+// is replaced by a snapshot of the primary. The store is synced before the
+// position is saved, so the follower never resumes past records it lost.
+pub(crate) struct Follower {
+    store: Arc<Mutex<KvStore>>,
+    primary: SocketAddr,
+    dirname: PathBuf,
+    position: Position,
+}
+
+
+impl Follower {
+
+    pub fn new(store: Arc<Mutex<KvStore>>, primary: SocketAddr) -> Result<Follower> {
+        let dirname = store.lock().map_err(|_| KvsError::Poisoned)?.path().to_owned();
+        let position = Position::load(&dirname)?;
+        // a snapshot that was not completely received is thrown away
+        let staging = staging_dir_path(&dirname);
+        if staging.exists() {
+            fs::remove_dir_all(staging)?;
+        }
+        Ok(Follower { store, primary, dirname, position })
+    }
+
+    // Replicate forever, reconnecting when the primary is unreachable.
+    pub fn run(mut self) {
+        loop {
+            if let Err(err) = self.follow() {
+                eprintln!("replication from {} failed: {}", self.primary, err);
+                thread::sleep(RETRY_INTERVAL);
+            }
+        }
+    }
+
+    fn follow(&mut self) -> Result<()> {
+        let mut client = KvsClient::connect(self.primary)?;
+        loop {
+            match client.fetch(self.position, FETCH_LIMIT)? {
+                Some((entries, next)) => {
+                    let idle = entries.is_empty();
+                    if !idle || next != self.position {
+                        let mut store = self.store.lock().map_err(|_| KvsError::Poisoned)?;
+                        if !idle {
+                            apply(&mut store, entries)?;
+                            store.sync()?;
+                        }
+                        next.save(&self.dirname)?;
+                        self.position = next;
+                    }
+                    if idle {
+                        thread::sleep(POLL_INTERVAL);
+                    }
+                },
+                None => self.catch_up(&mut client)?,
+            }
+        }
+    }
+
+    // Receive a snapshot into a store of its own and only replace the
+    // follower's store once it is complete. The store keeps serving reads
+    // while the snapshot is transferred.
+    fn catch_up(&mut self, client: &mut KvsClient) -> Result<()> {
+        let path = staging_dir_path(&self.dirname);
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let mut staging = KvStore::open(&path)?;
+        let position = client.snapshot(|pairs| staging.set_many(pairs))?;
+        {
+            let mut store = self.store.lock().map_err(|_| KvsError::Poisoned)?;
+            replace(&mut store, &staging)?;
+            store.sync()?;
+            position.save(&self.dirname)?;
+            self.position = position;
+            staging.close()?;
+            fs::remove_dir_all(path)?;
+        }
+        Ok(())
+    }
+
+}
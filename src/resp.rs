@@ -0,0 +1,441 @@
+use std::{
+    self,
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::batch::WriteBatch;
+use crate::error::*;
+use crate::scan::glob_match;
+use crate::shard;
+use crate::thread_pool;
+use crate::KvStore;
+
+
+// Largest bulk string and array accepted from a client. Redis allows bulk
+// strings of 512MiB, far more than a value of this store should hold.
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+
+// ~~~~~ Value ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// A RESP reply, `Bulk(None)` is the null bulk string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Value>),
+}
+
+
+impl Value {
+
+    fn ok() -> Value {
+        Value::Simple("OK".to_owned())
+    }
+
+    fn err(msg: &str) -> Value {
+        Value::Error(format!("ERR {}", msg))
+    }
+
+    fn wrong_arity(name: &str) -> Value {
+        Value::err(&format!("wrong number of arguments for '{}' command", name))
+    }
+
+    fn bulks<I: IntoIterator<Item = String>>(items: I) -> Value {
+        Value::Array(items.into_iter().map(|item| Value::Bulk(Some(item))).collect())
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Value::Simple(s) => write!(writer, "+{}\r\n", s)?,
+            Value::Error(s) => write!(writer, "-{}\r\n", s)?,
+            Value::Integer(i) => write!(writer, ":{}\r\n", i)?,
+            Value::Bulk(None) => write!(writer, "$-1\r\n")?,
+            Value::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s)?,
+            Value::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write_to(writer)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+}
+
+
+fn protocol_error(msg: &str) -> KvsError {
+    KvsError::Protocol(msg.to_owned())
+}
+
+
+// A line without its line ending. It is read as bytes, so an argument that
+// is not UTF-8 fails only its command.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+
+fn parse_len(s: &[u8], max: usize) -> Result<usize> {
+    match std::str::from_utf8(s).ok().and_then(|s| s.parse::<usize>().ok()) {
+        Some(len) if len <= max => Ok(len),
+        _ => Err(protocol_error("invalid length")),
+    }
+}
+
+
+// Read a command, sent as an array of bulk strings or as an inline command.
+// None at the end of the stream.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if !line.starts_with(b"*") {
+            let args: Vec<Vec<u8>> = line.split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            match args.is_empty() {
+                true => continue,
+                false => return Ok(Some(args)),
+            }
+        }
+        let count = parse_len(&line[1..], MAX_ARRAY_LEN)?;
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            let header = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end of stream"))?;
+            if !header.starts_with(b"$") {
+                let got = String::from_utf8_lossy(&header).chars().next().unwrap_or(' ');
+                return Err(protocol_error(&format!("expected '$', got '{}'", got)));
+            }
+            let len = parse_len(&header[1..], MAX_BULK_LEN)?;
+            // the buffer grows with the data that arrives, not with the
+            // length the client claims
+            let mut buf = Vec::new();
+            reader.by_ref().take(len as u64 + 2).read_to_end(&mut buf)?;
+            if buf.len() < len + 2 {
+                return Err(protocol_error("unexpected end of stream"));
+            }
+            if &buf[len..] != b"\r\n" {
+                return Err(protocol_error("bulk string not terminated by CRLF"));
+            }
+            buf.truncate(len);
+            args.push(buf);
+        }
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+
+// ~~~~~ Expiries ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// The deadlines of keys set with `EX` or `PX`. They are kept in memory only,
+// so keys do not expire after a restart of the server.
+#[derive(Debug, Default)]
+pub(crate) struct Expiries {
+    deadlines: HashMap<String, Instant>,
+}
+
+
+impl Expiries {
+
+    pub fn set(&mut self, key: &str, deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => self.deadlines.insert(key.to_owned(), deadline),
+            None => self.deadlines.remove(key),
+        };
+    }
+
+    pub fn take_expired(&mut self, key: &str) -> bool {
+        match self.deadlines.get(key) {
+            Some(deadline) if *deadline <= Instant::now() => self.deadlines.remove(key).is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn take_all_expired(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let expired: Vec<String> = self.deadlines.iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired.iter() {
+            self.deadlines.remove(key);
+        }
+        expired
+    }
+
+}
+
+
+// Remove the expired keys from the store.
+pub(crate) fn purge_expired(store: &mut KvStore, expiries: &Mutex<Expiries>) -> Result<()> {
+    let expired = expiries.lock().map_err(|_| KvsError::Poisoned)?.take_all_expired();
+    let mut batch = WriteBatch::new();
+    for key in expired {
+        if store.contains_key(&key)? {
+            batch.remove(key);
+        }
+    }
+    store.write(batch)
+}
+
+
+// ~~~~~ RespHandler ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Serves a connection speaking the Redis protocol, every command is run
+// against the store while holding it.
+pub(crate) struct RespHandler {
+    pub store: Arc<Mutex<KvStore>>,
+    pub expiries: Arc<Mutex<Expiries>>,
+    pub follower: bool,
+}
+
+
+impl RespHandler {
+
+    pub fn serve(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        loop {
+            let args = match read_command(&mut reader) {
+                Ok(Some(args)) => args,
+                Ok(None) => return writer.flush().map_err(KvsError::from),
+                Err(KvsError::Protocol(msg)) => {
+                    Value::err(&format!("Protocol error: {}", msg)).write_to(&mut writer)?;
+                    return writer.flush().map_err(KvsError::from);
+                },
+                Err(err) => return Err(err),
+            };
+            let args: Option<Vec<String>> = args.into_iter().map(|arg| String::from_utf8(arg).ok()).collect();
+            let quit = args.as_ref().is_some_and(|args| args[0].eq_ignore_ascii_case("quit"));
+            let reply = match args {
+                _ if quit => Value::ok(),
+                Some(args) => self.command(&args).unwrap_or_else(|err| Value::err(&err.to_string())),
+                // the whole command was read, the connection can go on
+                None => Value::err("arguments must be valid UTF-8"),
+            };
+            reply.write_to(&mut writer)?;
+            // answer pipelined commands together
+            if quit || reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, KvStore>> {
+        self.store.lock().map_err(|_| KvsError::Poisoned)
+    }
+
+    fn expiries(&self) -> Result<MutexGuard<'_, Expiries>> {
+        self.expiries.lock().map_err(|_| KvsError::Poisoned)
+    }
+
+    // Remove `key` from the store when it expired.
+    fn purge(&self, store: &mut KvStore, key: &str) -> Result<()> {
+        if self.expiries()?.take_expired(key) {
+            match store.remove(key.to_owned()) {
+                Ok(()) | Err(KvsError::KeyNotFound) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn command(&self, args: &[String]) -> Result<Value> {
+        let (command, args) = (&args[0], &args[1..]);
+        let name = command.to_ascii_lowercase();
+        let write = matches!(name.as_str(), "set" | "del" | "mset");
+        if write && self.follower {
+            return Ok(Value::Error("READONLY You can't write against a read only replica.".to_owned()));
+        }
+        let mut store = self.lock()?;
+        thread_pool::catch_panic(|| {
+            let reply = match name.as_str() {
+                "ping" => match args {
+                    [] => Value::Simple("PONG".to_owned()),
+                    [msg] => Value::Bulk(Some(msg.clone())),
+                    _ => Value::wrong_arity(&name),
+                },
+                "get" => match args {
+                    [key] => {
+                        self.purge(&mut store, key)?;
+                        Value::Bulk(store.get(key.clone())?)
+                    },
+                    _ => Value::wrong_arity(&name),
+                },
+                "set" if args.len() >= 2 => self.set(&mut store, args)?,
+                "mget" if !args.is_empty() => {
+                    let mut values = Vec::with_capacity(args.len());
+                    for key in args {
+                        self.purge(&mut store, key)?;
+                        values.push(Value::Bulk(store.get(key.clone())?));
+                    }
+                    Value::Array(values)
+                },
+                "mset" if !args.is_empty() && args.len() % 2 == 0 => {
+                    let mut batch = WriteBatch::new();
+                    let mut expiries = self.expiries()?;
+                    for pair in args.chunks(2) {
+                        expiries.set(&pair[0], None);
+                        batch.set(pair[0].clone(), pair[1].clone());
+                    }
+                    store.write(batch)?;
+                    Value::ok()
+                },
+                "del" if !args.is_empty() => {
+                    let mut batch = WriteBatch::new();
+                    let mut removed = HashSet::new();
+                    for key in args {
+                        self.purge(&mut store, key)?;
+                        if store.contains_key(key)? && removed.insert(key) {
+                            batch.remove(key.clone());
+                        }
+                    }
+                    store.write(batch)?;
+                    let mut expiries = self.expiries()?;
+                    for key in removed.iter() {
+                        expiries.set(key, None);
+                    }
+                    Value::Integer(removed.len() as i64)
+                },
+                "exists" if !args.is_empty() => {
+                    let mut count = 0;
+                    for key in args {
+                        self.purge(&mut store, key)?;
+                        count += store.contains_key(key)? as i64;
+                    }
+                    Value::Integer(count)
+                },
+                "keys" => match args {
+                    [pattern] => {
+                        purge_expired(&mut store, &self.expiries)?;
+                        Value::bulks(store.keys()?.into_iter().filter(|key| glob_match(pattern, key)))
+                    },
+                    _ => Value::wrong_arity(&name),
+                },
+                "scan" if !args.is_empty() => self.scan(&mut store, args)?,
+                "dbsize" => match args {
+                    [] => {
+                        purge_expired(&mut store, &self.expiries)?;
+                        Value::Integer(store.len() as i64)
+                    },
+                    _ => Value::wrong_arity(&name),
+                },
+                "set" | "mget" | "mset" | "del" | "exists" | "scan" => Value::wrong_arity(&name),
+                _ => Value::err(&format!("unknown command '{}'", command)),
+            };
+            Ok(reply)
+        })
+    }
+
+    // SET key value [EX seconds | PX milliseconds] [NX | XX]
+    fn set(&self, store: &mut KvStore, args: &[String]) -> Result<Value> {
+        let (key, value) = (&args[0], &args[1]);
+        let (mut ttl, mut nx, mut xx) = (None, false, false);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_lowercase().as_str() {
+                "nx" if !xx => nx = true,
+                "xx" if !nx => xx = true,
+                unit @ "ex" | unit @ "px" if ttl.is_none() => {
+                    let amount = match options.next().map(|amount| amount.parse::<u64>()) {
+                        Some(Ok(amount)) => amount,
+                        Some(Err(_)) => return Ok(Value::err("value is not an integer or out of range")),
+                        None => return Ok(Value::err("syntax error")),
+                    };
+                    if amount == 0 {
+                        return Ok(Value::err("invalid expire time in 'set' command"));
+                    }
+                    ttl = Some(match unit {
+                        "ex" => Duration::from_secs(amount),
+                        _ => Duration::from_millis(amount),
+                    });
+                },
+                _ => return Ok(Value::err("syntax error")),
+            }
+        }
+        self.purge(store, key)?;
+        if nx || xx {
+            let exists = store.contains_key(key)?;
+            if (nx && exists) || (xx && !exists) {
+                return Ok(Value::Bulk(None));
+            }
+        }
+        store.set(key.clone(), value.clone())?;
+        self.expiries()?.set(key, ttl.map(|ttl| Instant::now() + ttl));
+        Ok(Value::ok())
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]
+    //
+    // Keys are visited in the order of their hash and the cursor is the hash
+    // to continue from, so keys that exist for the whole scan are returned
+    // even when others are added or removed in between.
+    fn scan(&self, store: &mut KvStore, args: &[String]) -> Result<Value> {
+        let cursor = match args[0].parse::<u64>() {
+            Ok(cursor) => cursor,
+            Err(_) => return Ok(Value::err("invalid cursor")),
+        };
+        let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            match (option.to_ascii_lowercase().as_str(), options.next()) {
+                ("match", Some(p)) => pattern = Some(p.clone()),
+                ("count", Some(n)) => match n.parse::<usize>() {
+                    Ok(n) if n > 0 => count = n,
+                    _ => return Ok(Value::err("value is not an integer or out of range")),
+                },
+                _ => return Ok(Value::err("syntax error")),
+            }
+        }
+        purge_expired(store, &self.expiries)?;
+        let mut keys: Vec<(u64, String)> = store.keys()?.into_iter()
+            .map(|key| (shard::hash(key.as_bytes()), key))
+            .filter(|(hash, _)| *hash >= cursor)
+            .collect();
+        keys.sort();
+        // never split keys with the same hash over two pages
+        let mut end = keys.len().min(count);
+        while end < keys.len() && keys[end].0 == keys[end - 1].0 {
+            end += 1;
+        }
+        let next = match end < keys.len() {
+            true => keys[end - 1].0.checked_add(1).unwrap_or(0),
+            false => 0,
+        };
+        keys.truncate(end);
+        let page = keys.into_iter()
+            .map(|(_, key)| key)
+            .filter(|key| pattern.as_ref().is_none_or(|pattern| glob_match(pattern, key)));
+        Ok(Value::Array(vec![Value::Bulk(Some(next.to_string())), Value::bulks(page)]))
+    }
+
+}
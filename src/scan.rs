@@ -0,0 +1,101 @@
+use std::{
+    self,
+    vec,
+};
+
+use crate::error::*;
+use crate::log::{Entry, Log, LogPointer};
+
+
+type KvsEntry = Entry<String, String>;
+
+
+// Match `key` against a glob pattern: `*` matches any sequence, `?` any
+// single character, `[abc]` and `[a-z]` a character class (`[^...]` negates
+// it) and `\\` escapes the next character.
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    glob_match_at(&pattern, &key)
+}
+
+
+fn glob_match_at(pattern: &[char], key: &[char]) -> bool {
+    match pattern.first() {
+        None => key.is_empty(),
+        Some('*') => (0..=key.len()).any(|i| glob_match_at(&pattern[1..], &key[i..])),
+        Some('?') => !key.is_empty() && glob_match_at(&pattern[1..], &key[1..]),
+        Some('[') => {
+            let c = match key.first() {
+                Some(c) => *c,
+                None => return false,
+            };
+            match match_class(&pattern[1..], c) {
+                Some((true, rest)) => glob_match_at(rest, &key[1..]),
+                Some((false, _)) => false,
+                // an unterminated class matches a literal '['
+                None => c == '[' && glob_match_at(&pattern[1..], &key[1..]),
+            }
+        },
+        Some('\\') if pattern.len() > 1 => {
+            key.first() == Some(&pattern[1]) && glob_match_at(&pattern[2..], &key[1..])
+        },
+        Some(p) => key.first() == Some(p) && glob_match_at(&pattern[1..], &key[1..]),
+    }
+}
+
+
+// Match `c` against the class starting after '[', returning whether it
+// matched and the pattern following the closing ']'.
+fn match_class(pattern: &[char], c: char) -> Option<(bool, &[char])> {
+    let (negate, mut i) = match pattern.first() {
+        Some('^') | Some('!') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        if pattern[i] == ']' && !first {
+            return Some((matched != negate, &pattern[i + 1..]));
+        }
+        first = false;
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            matched |= pattern[i] <= c && c <= pattern[i + 2];
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    None
+}
+
+
+// Iterator over key/value pairs in key order, see `KvStore::scan`.
+pub struct Scan<'a> {
+    log: &'a Log,
+    pairs: vec::IntoIter<(String, LogPointer)>,
+}
+
+
+impl<'a> Scan<'a> {
+    pub(crate) fn new(log: &'a Log, mut pairs: Vec<(String, LogPointer)>) -> Scan<'a> {
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        Scan { log, pairs: pairs.into_iter() }
+    }
+}
+
+
+impl<'a> Iterator for Scan<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pairs.next().map(|(key, lp)| {
+            match self.log.retrieve(&lp)? {
+                KvsEntry::Set(_, value) => Ok((key, value)),
+                KvsEntry::Remove(_) => Err(lp.invalid()),
+            }
+        })
+    }
+
+}
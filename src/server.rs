@@ -0,0 +1,429 @@
+use std::{
+    self,
+    io::{BufReader, BufWriter, Write},
+    mem,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    ops::Bound,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::path::Path;
+use serde_json;
+
+use crate::error::*;
+#[cfg(feature = "http")]
+use crate::http::HttpHandler;
+use crate::protocol::{Request, Response};
+use crate::raft::{Command, Member, Raft};
+use crate::replication::{self, Follower};
+use crate::resp::{self, Expiries, RespHandler};
+use crate::thread_pool::{self, NaiveThreadPool, Spawn, ThreadPool};
+#[cfg(unix)]
+use crate::transport;
+use crate::transport::Stream;
+use crate::KvStore;
+
+
+// Number of pairs per chunk when streaming a snapshot.
+const SNAPSHOT_CHUNK: usize = 1000;
+
+// How often keys set to expire through RESP are removed.
+const EXPIRY_INTERVAL: Duration = Duration::from_millis(100);
+
+// Permissions of a Unix domain socket: only its owner can connect.
+#[cfg(unix)]
+const DEFAULT_SOCKET_MODE: u32 = 0o600;
+
+
+// Serves a store over TCP or a Unix domain socket. A primary accepts all requests, a follower
+// replicates the primary it follows and rejects writes from clients. A
+// cluster node replicates writes through Raft and serves reads locally.
+// Besides its own protocol a server can serve Redis clients on a second
+// address and, with the `http` feature, a REST interface on a third.
+// Every connection is served by a job on the server's thread pool, which
+// holds one of its threads until the connection closes.
+pub struct KvsServer {
+    store: Arc<Mutex<KvStore>>,
+    primary: Option<SocketAddr>,
+    cluster: Option<(u64, Vec<Member>)>,
+    resp_addr: Option<SocketAddr>,
+    #[cfg(feature = "http")]
+    http_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    socket_mode: u32,
+    expiries: Arc<Mutex<Expiries>>,
+    pool: Arc<dyn Spawn>,
+}
+
+
+impl KvsServer {
+
+    pub fn new(store: KvStore) -> KvsServer {
+        KvsServer {
+            store: Arc::new(Mutex::new(store)),
+            primary: None,
+            cluster: None,
+            resp_addr: None,
+            #[cfg(feature = "http")]
+            http_addr: None,
+            #[cfg(unix)]
+            socket_mode: DEFAULT_SOCKET_MODE,
+            expiries: Arc::new(Mutex::new(Expiries::default())),
+            pool: Arc::new(NaiveThreadPool),
+        }
+    }
+
+    // Run as a follower of the primary at `addr`.
+    pub fn follow<A: ToSocketAddrs>(mut self, addr: A) -> Result<KvsServer> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| KvsError::Protocol("no address for the primary".to_owned()))?;
+        self.primary = Some(addr);
+        Ok(self)
+    }
+
+    // Run as node `id` of a cluster. `members` bootstraps a new cluster and is
+    // ignored once the node has state, a node without members waits to be
+    // added to an existing cluster.
+    pub fn cluster(mut self, id: u64, members: Vec<Member>) -> KvsServer {
+        self.cluster = Some((id, members));
+        self
+    }
+
+    // Also accept Redis clients speaking RESP on `addr`.
+    pub fn resp<A: ToSocketAddrs>(mut self, addr: A) -> Result<KvsServer> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| KvsError::InvalidOptions("no address for RESP".to_owned()))?;
+        self.resp_addr = Some(addr);
+        Ok(self)
+    }
+
+    // Also serve the REST interface on `addr`.
+    #[cfg(feature = "http")]
+    pub fn http<A: ToSocketAddrs>(mut self, addr: A) -> Result<KvsServer> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| KvsError::InvalidOptions("no address for HTTP".to_owned()))?;
+        self.http_addr = Some(addr);
+        Ok(self)
+    }
+
+    // The permissions of the socket created by `run_unix`, only users allowed
+    // to write to it can connect.
+    #[cfg(unix)]
+    pub fn socket_mode(mut self, mode: u32) -> KvsServer {
+        self.socket_mode = mode;
+        self
+    }
+
+    // Serve the connections of both protocols on `pool` rather than a thread
+    // each. A pool of N threads serves N connections at once and the others
+    // wait, so a cluster node needs a thread for each of its peers as well.
+    pub fn pool<P: ThreadPool + Send + Sync + 'static>(mut self, pool: P) -> KvsServer {
+        self.pool = Arc::new(pool);
+        self
+    }
+
+    // A handle that syncs the store when the process is about to exit.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle { store: Arc::clone(&self.store) }
+    }
+
+    pub fn is_follower(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    // Accept connections on `addr` until the process exits.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.check()?;
+        let listener = TcpListener::bind(addr)?;
+        let raft = self.start()?;
+        self.accept(listener.incoming(), raft)
+    }
+
+    // Like `run`, but accept connections on a Unix domain socket at `path`.
+    // Cluster nodes need a TCP address their peers can reach.
+    #[cfg(unix)]
+    pub fn run_unix<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        if self.cluster.is_some() {
+            return Err(KvsError::InvalidOptions("a cluster node cannot listen on a Unix socket".to_owned()));
+        }
+        self.check()?;
+        let listener = transport::bind_unix(path.as_ref(), self.socket_mode)?;
+        let raft = self.start()?;
+        self.accept(listener.incoming(), raft)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.primary.is_some() && self.cluster.is_some() {
+            return Err(KvsError::InvalidOptions("a cluster node cannot follow a primary".to_owned()));
+        }
+        if self.resp_addr.is_some() && self.cluster.is_some() {
+            return Err(KvsError::InvalidOptions("RESP writes are not replicated in a cluster".to_owned()));
+        }
+        #[cfg(feature = "http")]
+        {
+            if self.http_addr.is_some() && self.cluster.is_some() {
+                return Err(KvsError::InvalidOptions("HTTP writes are not replicated in a cluster".to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    // Start the RESP and HTTP listeners, replication and Raft.
+    fn start(&self) -> Result<Option<Arc<Raft>>> {
+        if let Some(resp_addr) = self.resp_addr {
+            self.serve_resp(TcpListener::bind(resp_addr)?);
+        }
+        #[cfg(feature = "http")]
+        {
+            if let Some(http_addr) = self.http_addr {
+                let handler = HttpHandler {
+                    store: Arc::clone(&self.store),
+                    expiries: Arc::clone(&self.expiries),
+                    follower: self.is_follower(),
+                };
+                handler.serve(http_addr)?;
+            }
+        }
+        if let Some(primary) = self.primary {
+            let follower = Follower::new(Arc::clone(&self.store), primary)?;
+            thread::spawn(move || follower.run());
+        }
+        match self.cluster {
+            Some((id, ref members)) => Ok(Some(Raft::start(Arc::clone(&self.store), id, members.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    // Serve every connection on the pool.
+    fn accept<S, I>(&self, incoming: I, raft: Option<Arc<Raft>>) -> Result<()>
+        where S: Stream, I: Iterator<Item = std::io::Result<S>>
+    {
+        for stream in incoming {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("failed to accept a connection: {}", err);
+                    continue;
+                },
+            };
+            let handler = Handler {
+                store: Arc::clone(&self.store),
+                expiries: Arc::clone(&self.expiries),
+                follower: self.is_follower(),
+                raft: raft.clone(),
+            };
+            self.pool.spawn_job(Box::new(move || {
+                if let Err(err) = handler.serve(stream) {
+                    eprintln!("connection failed: {}", err);
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    // Accept RESP connections on their own thread and serve them on the pool,
+    // a primary also removes the expired keys in the background.
+    fn serve_resp(&self, listener: TcpListener) {
+        if !self.is_follower() {
+            let (store, expiries) = (Arc::clone(&self.store), Arc::clone(&self.expiries));
+            thread::spawn(move || loop {
+                thread::sleep(EXPIRY_INTERVAL);
+                let result = store.lock().map_err(|_| KvsError::Poisoned)
+                    .and_then(|mut store| resp::purge_expired(&mut store, &expiries));
+                if let Err(err) = result {
+                    eprintln!("failed to remove expired keys: {}", err);
+                }
+            });
+        }
+        let (store, expiries, follower) = (Arc::clone(&self.store), Arc::clone(&self.expiries), self.is_follower());
+        let pool = Arc::clone(&self.pool);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        eprintln!("failed to accept a RESP connection: {}", err);
+                        continue;
+                    },
+                };
+                let handler = RespHandler {
+                    store: Arc::clone(&store),
+                    expiries: Arc::clone(&expiries),
+                    follower,
+                };
+                pool.spawn_job(Box::new(move || {
+                    if let Err(err) = handler.serve(stream) {
+                        eprintln!("RESP connection failed: {}", err);
+                    }
+                }));
+            }
+        });
+    }
+
+}
+
+
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    store: Arc<Mutex<KvStore>>,
+}
+
+
+impl ShutdownHandle {
+
+    // Sync the store and keep it locked, so no write can follow before the
+    // process exits. Call it once, right before exiting.
+    pub fn shutdown(&self) -> Result<()> {
+        let mut store = self.store.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = store.sync();
+        mem::forget(store);
+        result
+    }
+
+}
+
+
+struct Handler {
+    store: Arc<Mutex<KvStore>>,
+    expiries: Arc<Mutex<Expiries>>,
+    follower: bool,
+    raft: Option<Arc<Raft>>,
+}
+
+
+impl Handler {
+
+    fn serve<S: Stream>(&self, stream: S) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        // a deserializer per request, so the reader shows whether more
+        // requests already arrived
+        while let Some(request) = serde_json::Deserializer::from_reader(&mut reader).into_iter::<Request>().next() {
+            match request? {
+                Request::Snapshot => {
+                    if let Err(err) = self.snapshot(&mut writer) {
+                        serde_json::to_writer(&mut writer, &Response::from(err))?;
+                    }
+                },
+                Request::Tagged { id, request } => {
+                    let response = match *request {
+                        Request::Snapshot | Request::Tagged { .. } => {
+                            Response::Err("a snapshot or tagged request cannot be tagged".to_owned())
+                        },
+                        request => self.handle(request),
+                    };
+                    serde_json::to_writer(&mut writer, &Response::Tagged { id, response: Box::new(response) })?;
+                },
+                request => serde_json::to_writer(&mut writer, &self.handle(request))?,
+            }
+            // answer pipelined requests together
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, KvStore>> {
+        self.store.lock().map_err(|_| KvsError::Poisoned)
+    }
+
+    // Writes on a cluster node wait for Raft, so they must not hold the store.
+    fn handle(&self, request: Request) -> Response {
+        let raft = match self.raft {
+            Some(ref raft) => raft,
+            None => return self.handle_local(request),
+        };
+        let response = match request {
+            Request::Set { key, value } => raft.propose(Command::Set { key, value }),
+            Request::Remove { key } => raft.propose(Command::Remove { key }),
+            Request::SetMany { pairs } => raft.propose(Command::SetMany { pairs }),
+            Request::RemoveMany { keys } => raft.propose(Command::RemoveMany { keys }),
+            Request::Raft(message) => raft.handle(message).map(Response::Raft),
+            Request::AddNode { id, addr } => raft.add_member(Member { id, addr }),
+            Request::RemoveNode { id } => raft.remove_member(id),
+            Request::Status => raft.status().map(Response::Status),
+            request => return self.handle_local(request),
+        };
+        response.unwrap_or_else(Response::from)
+    }
+
+    fn handle_local(&self, request: Request) -> Response {
+        let result = self.lock().and_then(|mut store| {
+            thread_pool::catch_panic(|| Ok(self.handle_store(&mut store, request)))
+        });
+        result.unwrap_or_else(Response::from)
+    }
+
+    fn handle_store(&self, store: &mut KvStore, request: Request) -> Response {
+        match request {
+            Request::Get { key } => match store.get(key) {
+                Ok(value) => Response::Value(value),
+                Err(err) => Response::from(err),
+            },
+            Request::GetMany { keys } => {
+                match keys.into_iter().map(|key| store.get(key)).collect::<Result<Vec<_>>>() {
+                    Ok(values) => Response::Values(values),
+                    Err(err) => Response::from(err),
+                }
+            },
+            Request::Scan { from, to, limit } => {
+                let range = (from.map_or(Bound::Unbounded, Bound::Included), to.map_or(Bound::Unbounded, Bound::Excluded));
+                match store.scan(range).and_then(|scan| scan.take(limit).collect::<Result<Vec<_>>>()) {
+                    Ok(pairs) => Response::Pairs(pairs),
+                    Err(err) => Response::from(err),
+                }
+            },
+            Request::Set { .. } | Request::Remove { .. } | Request::SetMany { .. } | Request::RemoveMany { .. }
+                if self.follower =>
+            {
+                Response::Err("the server is a read-only follower".to_owned())
+            },
+            // a write replaces the expiry a RESP client set
+            Request::Set { key, value } => Response::from_result(self.clear_expiry(&key).and_then(|_| store.set(key, value))),
+            Request::Remove { key } => Response::from_result(self.clear_expiry(&key).and_then(|_| store.remove(key))),
+            Request::SetMany { pairs } => {
+                let result = pairs.iter().try_for_each(|(key, _)| self.clear_expiry(key))
+                    .and_then(|_| store.set_many(pairs));
+                Response::from_result(result)
+            },
+            Request::RemoveMany { keys } => {
+                match keys.iter().try_for_each(|key| self.clear_expiry(key)).and_then(|_| store.remove_many(keys)) {
+                    Ok(count) => Response::Count(count),
+                    Err(err) => Response::from(err),
+                }
+            },
+            Request::Fetch { position, limit } => match store.fetch_records(position, limit) {
+                Ok(Some((entries, next))) => Response::Records { entries, next },
+                Ok(None) => Response::Compacted,
+                Err(err) => Response::from(err),
+            },
+            Request::Snapshot | Request::Tagged { .. } => Response::Err("unexpected snapshot or tagged request".to_owned()),
+            Request::Raft(_) | Request::AddNode { .. } | Request::RemoveNode { .. } | Request::Status => {
+                Response::Err("the server is not a cluster node".to_owned())
+            },
+        }
+    }
+
+    fn clear_expiry(&self, key: &str) -> Result<()> {
+        self.expiries.lock().map_err(|_| KvsError::Poisoned)?.set(key, None);
+        Ok(())
+    }
+
+    // Collect the pairs while holding the lock and send them after releasing it.
+    fn snapshot<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let (pairs, position) = {
+            let store = self.lock()?;
+            let pairs = store.iter()?.collect::<Result<Vec<_>>>()?;
+            (pairs, replication::end_position(store.log())?)
+        };
+        for chunk in pairs.chunks(SNAPSHOT_CHUNK) {
+            serde_json::to_writer(&mut *writer, &Response::SnapshotChunk(chunk.to_vec()))?;
+        }
+        serde_json::to_writer(&mut *writer, &Response::SnapshotEnd { position })?;
+        Ok(())
+    }
+
+}
@@ -0,0 +1,230 @@
+use std::{
+    self,
+    collections::{hash_map, BTreeMap, HashMap},
+    net::SocketAddr,
+    thread,
+};
+
+use crate::client::KvsClient;
+use crate::error::*;
+
+
+// Number of points every server gets on the ring.
+const VIRTUAL_NODES: usize = 128;
+
+// Number of pairs per scan while rebalancing.
+const REBALANCE_PAGE: usize = 1000;
+
+
+// 64 bit FNV-1a, stable across processes and Rust versions so every client
+// places keys on the same servers. The final mix spreads keys that only differ
+// in their last bytes over the whole ring.
+pub(crate) fn hash(data: &[u8]) -> u64 {
+    let mut hash = data.iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3));
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+
+// ~~~~~ HashRing ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Consistent hashing: a key belongs to the first server point at or after the
+// hash of the key, wrapping around. Adding or removing a server only moves the
+// keys between its points and their predecessors.
+#[derive(Debug, Clone, Default)]
+pub struct HashRing {
+    points: BTreeMap<u64, SocketAddr>,
+    virtual_nodes: usize,
+}
+
+
+impl HashRing {
+
+    pub fn new(virtual_nodes: usize) -> HashRing {
+        HashRing { points: BTreeMap::new(), virtual_nodes }
+    }
+
+    pub fn add(&mut self, addr: SocketAddr) {
+        for i in 0..self.virtual_nodes {
+            self.points.insert(hash(format!("{}#{}", addr, i).as_bytes()), addr);
+        }
+    }
+
+    pub fn remove(&mut self, addr: SocketAddr) {
+        self.points.retain(|_, point| *point != addr);
+    }
+
+    // The server owning `key`, None when the ring is empty.
+    pub fn node(&self, key: &str) -> Option<SocketAddr> {
+        let hash = hash(key.as_bytes());
+        self.points.range(hash..).next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, addr)| *addr)
+    }
+
+}
+
+
+// ~~~~~ ShardedClient ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RebalanceStats {
+    pub scanned: usize,
+    pub moved: usize,
+}
+
+
+impl RebalanceStats {
+
+    fn add(&mut self, other: RebalanceStats) {
+        self.scanned += other.scanned;
+        self.moved += other.moved;
+    }
+
+}
+
+
+// Spreads the keys over several servers, each holding a shard. All clients of
+// the same servers must use the same list of servers. Writes to keys that are
+// being moved by a rebalance can be lost, so rebalance while writes are paused.
+pub struct ShardedClient {
+    ring: HashRing,
+    clients: HashMap<SocketAddr, KvsClient>,
+}
+
+
+impl ShardedClient {
+
+    pub fn connect(addrs: &[SocketAddr]) -> Result<ShardedClient> {
+        let mut sharded = ShardedClient { ring: HashRing::new(VIRTUAL_NODES), clients: HashMap::new() };
+        for addr in addrs {
+            sharded.clients.insert(*addr, KvsClient::connect(addr)?);
+            sharded.ring.add(*addr);
+        }
+        Ok(sharded)
+    }
+
+    pub fn servers(&self) -> Vec<SocketAddr> {
+        let mut servers: Vec<_> = self.clients.keys().cloned().collect();
+        servers.sort();
+        servers
+    }
+
+    // The server the key is stored on.
+    pub fn server_for(&self, key: &str) -> Result<SocketAddr> {
+        self.ring.node(key).ok_or_else(|| KvsError::InvalidOptions("the sharded client has no servers".to_owned()))
+    }
+
+    fn client(&mut self, addr: SocketAddr) -> Result<&mut KvsClient> {
+        self.clients.get_mut(&addr)
+            .ok_or_else(|| KvsError::InvalidOptions(format!("{} is not a server of the sharded client", addr)))
+    }
+
+    fn client_for(&mut self, key: &str) -> Result<&mut KvsClient> {
+        let addr = self.server_for(key)?;
+        self.client(addr)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.client_for(&key)?.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.client_for(&key)?.set(key, value)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.client_for(&key)?.remove(key)
+    }
+
+    // Get the values of `keys` in order, asking all shards at the same time.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut groups: HashMap<SocketAddr, (Vec<usize>, Vec<String>)> = HashMap::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            let group = groups.entry(self.server_for(&key)?).or_default();
+            group.0.push(i);
+            group.1.push(key);
+        }
+        let count = groups.values().map(|(positions, _)| positions.len()).sum();
+        let mut values = vec![None; count];
+        let results: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = self.clients.iter_mut()
+                .filter_map(|(addr, client)| groups.remove(addr).map(|group| (client, group)))
+                .map(|(client, (positions, keys))| scope.spawn(move || (positions, client.get_many(keys))))
+                .collect();
+            handles.into_iter().map(|handle| handle.join().expect("a shard thread panicked")).collect()
+        });
+        for (positions, result) in results {
+            for (i, value) in positions.into_iter().zip(result?) {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    // Add a server and move the keys it now owns to it.
+    pub fn add_server(&mut self, addr: SocketAddr) -> Result<RebalanceStats> {
+        if let hash_map::Entry::Vacant(entry) = self.clients.entry(addr) {
+            entry.insert(KvsClient::connect(addr)?);
+            self.ring.add(addr);
+        }
+        self.rebalance()
+    }
+
+    // Move all keys off a server and stop using it.
+    pub fn remove_server(&mut self, addr: SocketAddr) -> Result<RebalanceStats> {
+        if self.servers() == [addr] {
+            return Err(KvsError::InvalidOptions("the last server cannot be removed".to_owned()));
+        }
+        self.ring.remove(addr);
+        let stats = self.move_misplaced(addr)?;
+        self.clients.remove(&addr);
+        Ok(stats)
+    }
+
+    // Move every key that is not on the server owning it.
+    pub fn rebalance(&mut self) -> Result<RebalanceStats> {
+        let mut stats = RebalanceStats::default();
+        for addr in self.servers() {
+            stats.add(self.move_misplaced(addr)?);
+        }
+        Ok(stats)
+    }
+
+    // Stream the keys of `source` in pages and move the ones another server
+    // owns. A key is written to its owner before it is removed from `source`.
+    fn move_misplaced(&mut self, source: SocketAddr) -> Result<RebalanceStats> {
+        let mut stats = RebalanceStats::default();
+        let mut from = None;
+        loop {
+            let pairs = match self.clients.get_mut(&source) {
+                Some(client) => client.scan(from.take(), None, REBALANCE_PAGE)?,
+                None => return Ok(stats),
+            };
+            let last_page = pairs.len() < REBALANCE_PAGE;
+            stats.scanned += pairs.len();
+            // continue right after the last key of the page
+            from = pairs.last().map(|(key, _)| format!("{}\0", key));
+            for (key, value) in pairs {
+                let target = match self.ring.node(&key) {
+                    Some(target) if target != source => target,
+                    _ => continue,
+                };
+                self.client(target)?.set(key.clone(), value)?;
+                match self.client(source)?.remove(key) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => (),
+                    Err(err) => return Err(err),
+                }
+                stats.moved += 1;
+            }
+            if last_page {
+                return Ok(stats);
+            }
+        }
+    }
+
+}
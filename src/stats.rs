@@ -0,0 +1,100 @@
+use std::{
+    self,
+    fmt,
+    fs,
+};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::cache::CacheStats;
+use crate::error::*;
+use crate::format::HEADER_LEN;
+use crate::log::Log;
+
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PartitionStats {
+    pub file_name: String,
+    pub entries: u16,
+    pub bytes: u64,
+    pub active: bool,
+}
+
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StoreStats {
+    // Number of live keys, see `KvStore::len`.
+    pub live_keys: usize,
+    // Number of entries in the log including overwritten and removed ones.
+    pub log_entries: usize,
+    pub partitions: Vec<PartitionStats>,
+    pub total_bytes: u64,
+    // Bytes taken by the records of the live keys.
+    pub live_bytes: u64,
+    // Bytes taken by overwritten and removed records.
+    pub stale_bytes: u64,
+    // Fraction of the log that compaction would reclaim.
+    pub garbage_ratio: f64,
+    // Unix timestamp of the last compaction.
+    pub last_compaction: Option<i64>,
+    pub index_memory: usize,
+    pub cache: CacheStats,
+}
+
+
+impl StoreStats {
+
+    pub(crate) fn collect(log: &Log, live_keys: usize, live_bytes: u64, index_memory: usize, cache: CacheStats) -> Result<StoreStats> {
+        let mut partitions = vec![];
+        for (i, partition) in log.hist.iter().chain(Some(&log.active)).enumerate() {
+            partitions.push(PartitionStats {
+                file_name: partition.file_name(),
+                entries: partition.entry_count(),
+                bytes: fs::metadata(partition.full_path(&log.dirname))?.len(),
+                active: i == log.hist.len(),
+            });
+        }
+        let total_bytes: u64 = partitions.iter().map(|p| p.bytes).sum();
+        // the partition headers are no garbage, compaction writes them again
+        let headers = HEADER_LEN * partitions.len() as u64;
+        let stale_bytes = total_bytes.saturating_sub(headers).saturating_sub(live_bytes);
+        Ok(StoreStats {
+            live_keys,
+            log_entries: log.len(),
+            partitions,
+            total_bytes,
+            live_bytes,
+            stale_bytes,
+            garbage_ratio: if total_bytes == 0 { 0.0 } else { stale_bytes as f64 / total_bytes as f64 },
+            last_compaction: log.last_compaction,
+            index_memory,
+            cache,
+        })
+    }
+
+}
+
+
+impl fmt::Display for StoreStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let last_compaction = match self.last_compaction {
+            Some(ts) => OffsetDateTime::from_unix_timestamp(ts).format("%Y-%m-%d %H:%M:%S UTC"),
+            None => "never".to_owned(),
+        };
+        writeln!(f, "live keys:       {}", self.live_keys)?;
+        writeln!(f, "log entries:     {}", self.log_entries)?;
+        writeln!(f, "partitions:      {}", self.partitions.len())?;
+        for partition in &self.partitions {
+            writeln!(f, "  {}  {} entries  {} bytes{}", partition.file_name, partition.entries, partition.bytes,
+                     if partition.active { "  (active)" } else { "" })?;
+        }
+        writeln!(f, "total bytes:     {}", self.total_bytes)?;
+        writeln!(f, "live bytes:      {}", self.live_bytes)?;
+        writeln!(f, "stale bytes:     {}", self.stale_bytes)?;
+        writeln!(f, "garbage ratio:   {:.2}", self.garbage_ratio)?;
+        writeln!(f, "last compaction: {}", last_compaction)?;
+        writeln!(f, "index memory:    ~{} bytes", self.index_memory)?;
+        write!(f, "cache:           {} hits, {} misses, {} of {} bytes used",
+               self.cache.hits, self.cache.misses, self.cache.size, self.cache.capacity)
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    self,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+use rayon;
+
+use crate::error::*;
+
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+
+// Runs jobs on a number of threads. A job that panics only ends that job, the
+// thread that ran it goes on with the next one.
+pub trait ThreadPool {
+    fn new(threads: u32) -> Result<Self> where Self: Sized;
+
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static;
+}
+
+
+// The server holds its pool as a trait object, which needs a spawn that is
+// not generic.
+pub(crate) trait Spawn: Send + Sync {
+    fn spawn_job(&self, job: Job);
+}
+
+
+impl<P: ThreadPool + Send + Sync> Spawn for P {
+    fn spawn_job(&self, job: Job) {
+        self.spawn(job)
+    }
+}
+
+
+// Run `f` while the caller holds a lock, catching a panic before it unwinds
+// past the guard and poisons the mutex for every other connection.
+pub(crate) fn catch_panic<T, F: FnOnce() -> Result<T>>(f: F) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(Err(KvsError::Poisoned))
+}
+
+
+fn check_threads(threads: u32) -> Result<()> {
+    match threads {
+        0 => Err(KvsError::InvalidOptions("a thread pool needs at least one thread".to_owned())),
+        _ => Ok(()),
+    }
+}
+
+
+// ~~~~~ NaiveThreadPool ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Starts a new thread for every job, the number of threads is ignored. A
+// server on this pool serves every connection on a thread of its own.
+pub struct NaiveThreadPool;
+
+
+impl ThreadPool for NaiveThreadPool {
+
+    fn new(_threads: u32) -> Result<NaiveThreadPool> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        thread::spawn(job);
+    }
+
+}
+
+
+// ~~~~~ SharedQueueThreadPool ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// A fixed number of threads taking jobs from one queue. Jobs wait in the
+// queue while all threads are busy. The threads stop once the pool is dropped
+// and the queue is empty.
+pub struct SharedQueueThreadPool {
+    sender: mpsc::Sender<Job>,
+}
+
+
+impl ThreadPool for SharedQueueThreadPool {
+
+    fn new(threads: u32) -> Result<SharedQueueThreadPool> {
+        check_threads(threads)?;
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("kvs-worker-{}", i))
+                .spawn(move || run_jobs(&receiver))?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        self.sender.send(Box::new(job)).expect("the pool has no threads left");
+    }
+
+}
+
+
+fn run_jobs(receiver: &Mutex<mpsc::Receiver<Job>>) {
+    loop {
+        // release the lock before running the job
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match job {
+            Ok(job) => {
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    eprintln!("a job panicked in {}", thread::current().name().unwrap_or("a worker"));
+                }
+            },
+            Err(_) => return,
+        }
+    }
+}
+
+
+// ~~~~~ RayonThreadPool ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+
+impl ThreadPool for RayonThreadPool {
+
+    fn new(threads: u32) -> Result<RayonThreadPool> {
+        check_threads(threads)?;
+        // without a panic handler rayon aborts the process when a job panics
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .panic_handler(|_| eprintln!("a job panicked in the rayon pool"))
+            .build()?;
+        Ok(RayonThreadPool { pool })
+    }
+
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        self.pool.spawn(job);
+    }
+
+}
@@ -0,0 +1,144 @@
+use std::{
+    self,
+    collections::HashSet,
+    io::{BufRead, Write},
+    str::FromStr,
+};
+use serde::{Serialize, Deserialize};
+use serde_json;
+
+use crate::error::*;
+use crate::batch::WriteBatch;
+use crate::KvStore;
+
+
+// Number of records written to the log per batch while importing.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Record {
+    key: String,
+    value: String,
+}
+
+
+// ~~~~~ Format ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // One `{"key": ..., "value": ...}` object per line.
+    JsonLines,
+    // A `key,value` header followed by one record per row.
+    Csv,
+}
+
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Format, String> {
+        match s {
+            "jsonl" | "json" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {}, expected jsonl or csv", s)),
+        }
+    }
+}
+
+
+// ~~~~~ Conflict ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// What to do with imported keys that already exist in the store.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    #[default]
+    Replace,
+    SkipExisting,
+}
+
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportStats {
+    pub read: u64,
+    pub written: u64,
+    pub skipped: u64,
+}
+
+
+// Write all live key/value pairs to `writer`, returning the number of records.
+pub fn export<W: Write>(store: &KvStore, writer: W, format: Format) -> Result<u64> {
+    let mut count = 0;
+    match format {
+        Format::JsonLines => {
+            let mut writer = writer;
+            for pair in store.iter()? {
+                let (key, value) = pair?;
+                serde_json::to_writer(&mut writer, &Record { key, value })?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+            writer.flush()?;
+        },
+        Format::Csv => {
+            // the header row is written along with the first record
+            let mut writer = csv::Writer::from_writer(writer);
+            for pair in store.iter()? {
+                let (key, value) = pair?;
+                writer.serialize(Record { key, value })?;
+                count += 1;
+            }
+            writer.flush()?;
+        },
+    }
+    Ok(count)
+}
+
+
+// Read key/value pairs from `reader` and write them to the store in batches.
+// `progress` is called after every batch.
+pub fn import<R, F>(store: &mut KvStore, reader: R, format: Format, conflict: Conflict, mut progress: F) -> Result<ImportStats>
+    where
+        R: BufRead,
+        F: FnMut(&ImportStats),
+{
+    let records: Box<dyn Iterator<Item = Result<Record>>> = match format {
+        Format::JsonLines => Box::new(
+            reader.lines()
+                .filter(|line| line.as_ref().map_or(true, |l| !l.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?))
+        ),
+        Format::Csv => Box::new(
+            csv::Reader::from_reader(reader)
+                .into_deserialize()
+                .map(|record| record.map_err(KvsError::from))
+        ),
+    };
+    let mut stats = ImportStats::default();
+    let mut batch = WriteBatch::new();
+    // keys in the current batch, with `SkipExisting` a key repeated in the
+    // input keeps its first value
+    let mut batch_keys = HashSet::new();
+    for record in records {
+        let Record { key, value } = record?;
+        stats.read += 1;
+        if conflict == Conflict::SkipExisting && (batch_keys.contains(&key) || store.contains_key(&key)?) {
+            stats.skipped += 1;
+            continue;
+        }
+        batch_keys.insert(key.clone());
+        batch.set(key, value);
+        if batch.len() == IMPORT_BATCH_SIZE {
+            stats.written += batch.len() as u64;
+            store.write(std::mem::take(&mut batch))?;
+            batch_keys.clear();
+            progress(&stats);
+        }
+    }
+    if !batch.is_empty() {
+        stats.written += batch.len() as u64;
+        store.write(batch)?;
+        progress(&stats);
+    }
+    Ok(stats)
+}
@@ -0,0 +1,80 @@
+use std::{
+    self,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{fs::{DirBuilderExt, FileTypeExt, PermissionsExt}, net::{UnixListener, UnixStream}},
+    path::Path,
+    process,
+};
+
+use crate::error::*;
+
+
+// A connection the wire protocol can run over, TCP or a Unix domain socket.
+pub(crate) trait Stream: Read + Write + Send + Sync + 'static {
+    fn try_clone(&self) -> io::Result<Self> where Self: Sized;
+
+    // Fail every read and write, also those blocked on other handles.
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+
+// Listen on the socket at `path`, which only users matching `mode` can
+// connect to. A socket file left behind by a server that is gone is replaced,
+// one with a server listening on it is not.
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &Path, mode: u32) -> Result<UnixListener> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(KvsError::InvalidOptions(format!("{} exists and is not a socket", path.display())));
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => return Err(KvsError::from(io::Error::from(io::ErrorKind::AddrInUse))),
+            Err(_) => fs::remove_file(path)?,
+        }
+    }
+    // the socket is created with the umask applied, so it is bound in a
+    // directory only the owner can enter and moved into place once it has
+    // its final mode
+    let name = path.file_name().ok_or_else(|| KvsError::InvalidOptions(format!("{} is not a file", path.display())))?;
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join(name);
+    let result = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    fs::remove_dir(&private)?;
+    Ok(result?)
+}
@@ -0,0 +1,294 @@
+use std::{
+    self,
+    fmt,
+    io::{self, BufReader, Read},
+    fs::{self, File},
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+use serde_json;
+
+use crate::error::*;
+use crate::format::{PartitionHeader, HEADER_LEN};
+use crate::lock::{DirLock, LockMode};
+use crate::log::{Entry, Log, LogPartition};
+use crate::options::KvStoreOptions;
+
+
+type KvsEntry = Entry<String, String>;
+
+
+fn quarantine_dir_path(dirname: &Path) -> PathBuf {
+    let mut path = PathBuf::from(dirname);
+    path.push("quarantine");
+    path
+}
+
+
+// ~~~~~ Problem ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    // A partition listed in `logparts` has no file.
+    MissingPartition { file_name: String },
+    // A partition file is not listed in `logparts`.
+    OrphanedPartition { file_name: String },
+    // The partition file does not start with a valid header.
+    InvalidHeader { file_name: String },
+    // The data from `offset` to the end of the partition does not decode.
+    CorruptRecord { file_name: String, offset: u64 },
+    // `logparts` records a different number of entries than the partition holds.
+    EntryCountMismatch { file_name: String, recorded: u16, actual: u64 },
+    // An index entry does not point at a matching record.
+    DanglingPointer { key: Option<String>, partition: u32, offset: u64 },
+    // `logparts` is missing or does not decode, only the partition files in
+    // the directory were checked.
+    UnreadableMeta { error: String },
+}
+
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::MissingPartition { ref file_name } => {
+                write!(f, "missing partition file {}", file_name)
+            },
+            Problem::OrphanedPartition { ref file_name } => {
+                write!(f, "orphaned partition file {}", file_name)
+            },
+            Problem::InvalidHeader { ref file_name } => {
+                write!(f, "invalid header in partition file {}", file_name)
+            },
+            Problem::CorruptRecord { ref file_name, offset } => {
+                write!(f, "corrupt record in {} at offset {}", file_name, offset)
+            },
+            Problem::EntryCountMismatch { ref file_name, recorded, actual } => {
+                write!(f, "{} holds {} entries, logparts records {}", file_name, actual, recorded)
+            },
+            Problem::DanglingPointer { ref key, partition, offset } => {
+                match key {
+                    Some(key) => write!(f, "key {} points at partition {} offset {} without a matching record", key, partition, offset),
+                    None => write!(f, "index points at partition {} offset {} without a matching record", partition, offset),
+                }
+            },
+            Problem::UnreadableMeta { ref error } => {
+                write!(f, "logparts cannot be read, checked the partition files only: {}", error)
+            },
+        }
+    }
+}
+
+
+// ~~~~~ VerifyReport ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+#[derive(Debug, Default, Clone)]
+pub struct VerifyReport {
+    pub partitions: usize,
+    pub records: u64,
+    pub problems: Vec<Problem>,
+    // Set when the problems were repaired.
+    pub repaired: bool,
+}
+
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+
+// Walk all partitions of the log validating every record and cross-check
+// `logparts` against the partition files in the directory.
+pub(crate) fn check_log(log: &Log) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let partitions: Vec<&LogPartition> = log.hist.iter().chain(Some(&log.active)).collect();
+    let mut known = HashSet::new();
+    for partition in partitions {
+        let file_name = partition.file_name();
+        known.insert(file_name.clone());
+        let actual = match check_partition(&partition.full_path(&log.dirname), partition.file_id(), &mut report)? {
+            Some(actual) => actual,
+            None => continue,
+        };
+        if actual != partition.entry_count() as u64 {
+            report.problems.push(Problem::EntryCountMismatch {
+                file_name,
+                recorded: partition.entry_count(),
+                actual,
+            });
+        }
+    }
+    for path in partition_files(&log.dirname)? {
+        if let Some(file_name) = path.file_name().and_then(|name| name.to_str()) {
+            if !known.contains(file_name) {
+                report.problems.push(Problem::OrphanedPartition { file_name: file_name.to_owned() });
+            }
+        }
+    }
+    Ok(report)
+}
+
+
+// Check the records of the partition file at `path`, returning how many it
+// holds. None when the file is missing or has no valid header.
+fn check_partition(path: &Path, file_id: u128, report: &mut VerifyReport) -> Result<Option<u64>> {
+    let file_name = LogPartition::build_file_name(file_id);
+    report.partitions += 1;
+    let mut fh = match File::open(path) {
+        Ok(fh) => fh,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            report.problems.push(Problem::MissingPartition { file_name });
+            return Ok(None);
+        },
+        Err(err) => return Err(KvsError::from(err)),
+    };
+    match PartitionHeader::read_from(&mut fh, file_id) {
+        Ok(_) => {},
+        Err(KvsError::Io(err)) => return Err(KvsError::Io(err)),
+        Err(_) => {
+            report.problems.push(Problem::InvalidHeader { file_name });
+            return Ok(None);
+        },
+    }
+    let mut records = serde_json::Deserializer::from_reader(BufReader::new(fh)).into_iter::<KvsEntry>();
+    let mut actual = 0;
+    loop {
+        let offset = HEADER_LEN + records.byte_offset() as u64;
+        match records.next() {
+            Some(Ok(_)) => actual += 1,
+            Some(Err(_)) => {
+                report.problems.push(Problem::CorruptRecord { file_name, offset });
+                break;
+            },
+            None => break,
+        }
+    }
+    report.records += actual;
+    Ok(Some(actual))
+}
+
+
+fn partition_files(dirname: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for dir_entry in fs::read_dir(dirname)? {
+        let path = dir_entry?.path();
+        if path.is_file() && path.extension() == Some("dblog".as_ref()) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+
+// Without `logparts` only the partition files in the directory are checked.
+// Which of them make up the log cannot be told from the files alone, after
+// an interrupted compaction some hold keys that were removed since, so they
+// are reported but not repaired.
+fn check_partition_files(dirname: &Path, err: KvsError) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    report.problems.push(Problem::UnreadableMeta { error: err.to_string() });
+    for path in partition_files(dirname)? {
+        let file_id = path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| u128::from_str_radix(stem, 16).ok());
+        match file_id {
+            Some(file_id) => {
+                check_partition(&path, file_id, &mut report)?;
+            },
+            None => {
+                let file_name = path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+                report.problems.push(Problem::OrphanedPartition { file_name });
+            },
+        }
+    }
+    Ok(report)
+}
+
+
+// Verify the store in `dirname` without opening it, so that it also works on
+// stores too damaged to be opened. With `repair` the problems are fixed by
+// moving bad data into the `quarantine` directory and rewriting `logparts`.
+// When `logparts` itself cannot be read the partition files are checked
+// without repairing them.
+pub fn verify_dir(dirname: &Path, repair: bool) -> Result<VerifyReport> {
+    let mode = if repair { LockMode::Exclusive } else { LockMode::Shared };
+    let _lock = DirLock::acquire(dirname, mode)?;
+    // a read-only log never writes its meta data on drop, repairs write it explicitly
+    let mut log = match Log::open_read_only(dirname, &KvStoreOptions::default()) {
+        Ok(log) => log,
+        Err(err @ KvsError::CorruptMeta(_)) => return check_partition_files(dirname, err),
+        Err(KvsError::StoreNotFound) if !partition_files(dirname)?.is_empty() => {
+            return check_partition_files(dirname, KvsError::StoreNotFound);
+        },
+        Err(err) => return Err(err),
+    };
+    let mut report = check_log(&log)?;
+    if repair && !report.is_ok() {
+        repair_log(&mut log, &report.problems)?;
+        report.repaired = true;
+    }
+    Ok(report)
+}
+
+
+fn repair_log(log: &mut Log, problems: &[Problem]) -> Result<()> {
+    let quarantine = quarantine_dir_path(&log.dirname);
+    fs::create_dir_all(&quarantine)?;
+    for problem in problems {
+        match problem {
+            Problem::OrphanedPartition { file_name } => {
+                fs::rename(log.dirname.join(file_name), quarantine.join(file_name))?;
+            },
+            Problem::CorruptRecord { file_name, offset } => {
+                // move the undecodable tail aside and replace the partition by
+                // a copy without it, the file may be linked from elsewhere
+                let path = log.dirname.join(file_name);
+                let mut fh = File::open(&path)?;
+                let tmp_path = path.with_extension("repair");
+                let mut good = File::create(&tmp_path)?;
+                io::copy(&mut fh.by_ref().take(*offset), &mut good)?;
+                good.sync_all()?;
+                let mut bad = File::create(quarantine.join(format!("{}.{}", file_name, offset)))?;
+                io::copy(&mut fh, &mut bad)?;
+                bad.sync_all()?;
+                fs::rename(tmp_path, path)?;
+            },
+            Problem::InvalidHeader { file_name } => {
+                // without a valid header none of the records can be trusted
+                fs::rename(log.dirname.join(file_name), quarantine.join(file_name))?;
+                drop_partition(log, file_name)?;
+            },
+            Problem::MissingPartition { file_name } => {
+                drop_partition(log, file_name)?;
+            },
+            Problem::EntryCountMismatch { file_name, actual, .. } => {
+                let count = (*actual).min(u16::MAX as u64) as u16;
+                for partition in log.hist.iter_mut().chain(Some(&mut log.active)) {
+                    if &partition.file_name() == file_name {
+                        partition.entry_count = count;
+                    }
+                }
+            },
+            Problem::DanglingPointer { .. } => {
+                // the index is rebuilt from the log when the store is opened
+            },
+            Problem::UnreadableMeta { .. } => {
+                // only reported for stores that are not repaired
+            },
+        }
+    }
+    log.dump_meta()
+}
+
+
+fn drop_partition(log: &mut Log, file_name: &str) -> Result<()> {
+    log.hist.retain(|p| p.file_name() != file_name);
+    if log.active.file_name() == file_name {
+        let (mut active, _fh) = log.new_partition()?;
+        active.first_seq = log.active.end_seq();
+        log.active = active;
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use std::{
+    self,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    time::Duration,
+};
+use serde::Serialize;
+
+use crate::error::*;
+use crate::log::{Entry, Log, LogIter};
+
+
+type KvsEntry = Entry<String, String>;
+
+
+// ~~~~~ Event ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// A write to the store with the sequence number of its log record.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Set { seq: u64, key: String, value: String },
+    Remove { seq: u64, key: String },
+}
+
+
+impl Event {
+
+    fn from_entry(seq: u64, entry: KvsEntry) -> Event {
+        match entry {
+            Entry::Set(key, value) => Event::Set { seq, key, value },
+            Entry::Remove(key) => Event::Remove { seq, key },
+        }
+    }
+
+    pub fn seq(&self) -> u64 {
+        match *self {
+            Event::Set { seq, .. } | Event::Remove { seq, .. } => seq,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            Event::Set { key, .. } | Event::Remove { key, .. } => key,
+        }
+    }
+
+}
+
+
+// ~~~~~ Watcher ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Receives the events for keys with the watched prefix, in sequence order.
+// Events are buffered until they are received. Iterating blocks until the
+// next event and ends once the store is dropped.
+#[derive(Debug)]
+pub struct Watcher {
+    rx: Receiver<Event>,
+}
+
+
+impl Watcher {
+
+    // Returns None once the store is dropped and all events were received.
+    pub fn recv(&self) -> Option<Event> {
+        self.rx.recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Event> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<Event> {
+        match self.rx.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+}
+
+
+impl Iterator for Watcher {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.recv()
+    }
+
+}
+
+
+// The senders of all watchers, dropped watchers are pruned on the next publish.
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers {
+    senders: Vec<(String, Sender<Event>)>,
+}
+
+
+impl Subscribers {
+
+    pub fn subscribe(&mut self, prefix: &str) -> Watcher {
+        let (tx, rx) = mpsc::channel();
+        self.senders.push((prefix.to_owned(), tx));
+        Watcher { rx }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    pub fn publish(&mut self, event: &Event) {
+        self.senders.retain(|(prefix, tx)| {
+            !event.key().starts_with(prefix.as_str()) || tx.send(event.clone()).is_ok()
+        });
+    }
+
+}
+
+
+// ~~~~~ Changes ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
+
+// Replays the events after a sequence number from the log.
+pub struct Changes<'a> {
+    log: &'a Log,
+    iter: LogIter<'a, KvsEntry>,
+    since: u64,
+    // the partition ordinal of the last record and the sequence number of the next
+    current: Option<(u32, u64)>,
+}
+
+
+impl<'a> Changes<'a> {
+
+    pub(crate) fn new(log: &'a Log, since: u64) -> Result<Changes<'a>> {
+        if since < log.compacted_seq {
+            return Err(KvsError::ChangesCompacted { since, compacted: log.compacted_seq });
+        }
+        // skip the partitions that only hold older records
+        let skip = log.hist.iter().take_while(|p| p.end_seq() <= since + 1).count();
+        Ok(Changes { log, iter: log.iter_from(skip), since, current: None })
+    }
+
+}
+
+
+impl<'a> Iterator for Changes<'a> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (entry, lp) = match self.iter.next()? {
+                Ok(item) => item,
+                Err(err) => return Some(Err(err)),
+            };
+            let seq = match self.current {
+                Some((partition, seq)) if partition == lp.partition() => seq,
+                _ => match self.log.partition(lp.partition()) {
+                    Some(partition) => partition.first_seq(),
+                    None => return Some(Err(lp.invalid())),
+                },
+            };
+            self.current = Some((lp.partition(), seq + 1));
+            if seq > self.since {
+                return Some(Ok(Event::from_entry(seq, entry)));
+            }
+        }
+    }
+
+}
@@ -1,6 +1,9 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{format, glob_match, CompactionPolicy, Event, IndexMode, KvStore, KvStoreOptions, KvsClient, KvsError, KvsServer, Member, NaiveThreadPool, RayonThreadPool, Result, Role, ShardedClient, SharedQueueThreadPool, ThreadPool, WriteBatch};
+use kvs::transfer::{self, Conflict, Format};
+use kvs::protocol::Response;
 use predicates::ord::eq;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty, PredicateStrExt};
 use std::process::Command;
 use tempfile::TempDir;
@@ -17,7 +20,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,24 +31,25 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(eq("Key not found").trim());
 }
 
-// `kvs rm <KEY>` should print "Key not found" for an empty database and exit with non-zero code.
+// `kvs rm <KEY>` should report "Key not found" on stderr for an empty database and exit with code 2.
 #[test]
 fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
-        .stdout(eq("Key not found").trim());
+        .code(2)
+        .stdout(is_empty())
+        .stderr(contains("Key not found"));
 }
 
 // `kvs set <KEY> <VALUE>` should print nothing and exit with zero.
@@ -54,7 +58,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -72,7 +76,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +84,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -100,7 +104,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +112,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +125,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +140,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +161,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +176,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -299,3 +303,1686 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Values should be served from the cache after the first read or write.
+#[test]
+fn cache_hit_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.cache_stats().hits, 1);
+    assert_eq!(store.cache_stats().misses, 0);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    assert!((stats.hit_ratio() - 0.5).abs() < f64::EPSILON);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    // a remove is neither a hit nor a miss
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (2, 1));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.cache_stats().entries, 0);
+    Ok(())
+}
+
+// The cache should never grow beyond its capacity in bytes.
+#[test]
+fn cache_capacity() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_cache_capacity(20);
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let stats = store.cache_stats();
+    assert!(stats.size <= 20);
+    assert_eq!(stats.entries, 2);
+    // the least recently used keys were evicted
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.cache_stats().misses, 1);
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.cache_stats().hits, 1);
+
+    store.set_cache_capacity(0);
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.cache_stats().entries, 0);
+    Ok(())
+}
+
+// The compact index modes should behave like the default one.
+#[test]
+fn compact_index_modes() -> Result<()> {
+    for mode in &[IndexMode::Hashed, IndexMode::Spilled] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().index_mode(*mode).cache_capacity(0);
+        let mut store = options.open(temp_dir.path())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.set("key1".to_owned(), "value1b".to_owned())?;
+        store.remove("key2".to_owned())?;
+        assert_eq!(store.len(), 99);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1b".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert!(store.remove("key2".to_owned()).is_err());
+
+        drop(store);
+        let mut store = options.open(temp_dir.path())?;
+        assert_eq!(store.index_mode(), *mode);
+        assert_eq!(store.len(), 99);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1b".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+        store.set("key2".to_owned(), "value2b".to_owned())?;
+        store.remove("key3".to_owned())?;
+        assert_eq!(store.len(), 99);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2b".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// The spilled index should survive compaction.
+#[test]
+fn spilled_index_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index_mode(IndexMode::Spilled).cache_capacity(0);
+    let mut store = options.open(temp_dir.path())?;
+    for iter in 0..200 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert_eq!(store.len(), 1000);
+    drop(store);
+    let mut store = options.open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+    Ok(())
+}
+
+
+// Keys written to a spilled index are merged into its sorted file once there
+// are enough of them, rather than kept in memory.
+#[test]
+fn spilled_index_overlay_flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index_mode(IndexMode::Spilled).cache_capacity(0);
+    let mut store = options.open(temp_dir.path())?;
+    let mut memory = 0;
+    for key_id in 0..40_000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        if key_id % 2 == 1 {
+            store.remove(format!("key{}", key_id - 1))?;
+        }
+        memory = memory.max(store.index_memory());
+    }
+    assert_eq!(store.len(), 20_000);
+    assert!(store.index_memory() < memory);
+    for key_id in 0..40_000 {
+        let expected = if key_id % 2 == 1 { Some(format!("value{}", key_id)) } else { None };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    drop(store);
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.len(), 20_000);
+    assert_eq!(store.get("key39999".to_owned())?, Some("value39999".to_owned()));
+    assert_eq!(store.get("key39998".to_owned())?, None);
+    Ok(())
+}
+
+// A store directory can only be opened by one writer at a time.
+#[test]
+fn open_locked_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked { pid }) => assert_eq!(pid, Some(std::process::id())),
+        other => panic!("expected the store to be locked, got {:?}", other),
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(format!("locked by process {}", std::process::id())));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A read-only store should never write to its directory, except to create a
+// missing lock file.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let snapshot = || -> Vec<(std::path::PathBuf, Vec<u8>)> {
+        let mut files: Vec<_> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.is_file())
+            .map(|path| { let content = std::fs::read(&path).unwrap(); (path, content) })
+            .collect();
+        files.sort();
+        files
+    };
+    let before = snapshot();
+
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    assert!(store.is_read_only());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(store.set("key2".to_owned(), "value2".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked { .. })));
+    drop(store);
+    drop(reader);
+
+    assert_eq!(snapshot(), before);
+
+    // without a lock file a reader creates it to keep writers out
+    std::fs::remove_file(temp_dir.path().join("LOCK"))?;
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked { .. })));
+    drop(reader);
+    Ok(())
+}
+
+// Options should be validated against the store on open.
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+
+    let missing = KvStoreOptions::new().create_if_missing(false).open(&path);
+    assert!(matches!(missing, Err(KvsError::StoreNotFound)));
+    let invalid = KvStoreOptions::new().compaction(CompactionPolicy::Ratio { factor: 0, min_partitions: 1 }).open(&path);
+    assert!(matches!(invalid, Err(KvsError::InvalidOptions(_))));
+    assert!(!path.exists());
+
+    let options = KvStoreOptions::new().error_if_exists(true).max_partition_size(16);
+    let mut store = options.open(&path)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    // every entry is larger than the maximum partition size
+    let partitions = std::fs::read_dir(&path)?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("dblog".as_ref()))
+        .count();
+    assert_eq!(partitions, 10);
+    assert!(matches!(options.open(&path), Err(KvsError::StoreExists)));
+
+    let options = KvStoreOptions::new().create_if_missing(false).compaction(CompactionPolicy::Never);
+    let mut store = options.open(&path)?;
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    for _ in 0..100 {
+        store.set("key0".to_owned(), "value0".to_owned())?;
+    }
+    drop(store);
+    let partitions = std::fs::read_dir(&path)?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("dblog".as_ref()))
+        .count();
+    assert_eq!(partitions, 110);
+
+    // an explicit size replaces the stored one
+    let options = KvStoreOptions::new().compaction(CompactionPolicy::Never);
+    let mut store = options.clone().max_partition_size(1024 * 1024).open(&path)?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    drop(store);
+    let mut store = options.open(&path)?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    drop(store);
+    let partitions = std::fs::read_dir(&path)?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("dblog".as_ref()))
+        .count();
+    assert_eq!(partitions, 110);
+    Ok(())
+}
+
+// `kvs verify` should detect damage and `kvs verify --repair` quarantine it.
+#[test]
+fn cli_verify_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.verify()?.is_ok());
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 partitions, 2 records, 0 problems"));
+
+    let partition = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("dblog".as_ref()))
+        .unwrap();
+    let mut content = std::fs::read(&partition)?;
+    let valid_len = content.len();
+    content.extend_from_slice(b"{\"Set\":[\"key3\",");
+    std::fs::write(&partition, content)?;
+    std::fs::write(temp_dir.path().join("1.dblog"), b"")?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(format!("at offset {}", valid_len)).and(contains("orphaned partition file 1.dblog")));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", "--repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("(repaired)"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    assert!(temp_dir.path().join("quarantine").join("1.dblog").exists());
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    drop(store);
+
+    // without logparts the partition files are still checked, but not repaired
+    std::fs::write(temp_dir.path().join("logparts"), b"{")?;
+    for args in [&["verify"][..], &["verify", "--repair"]] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stdout(contains("logparts cannot be read").and(contains("1 partitions, 2 records, 1 problems\n")));
+    }
+    Ok(())
+}
+
+// A backup should hold the data at the time it was taken.
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (store_dir, backup_dir) = (temp_dir.path().join("store"), temp_dir.path().join("backup"));
+    let mut store = KvStoreOptions::new().max_partition_size(256).open(&store_dir)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.backup_to(&backup_dir)?;
+    assert!(matches!(store.backup_to(&backup_dir), Err(KvsError::StoreExists)));
+    // sealed partitions are shared with the store, the active one is copied
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let linked = std::fs::read_dir(&backup_dir)?
+            .map(|entry| entry.and_then(|entry| entry.metadata()))
+            .collect::<std::io::Result<Vec<_>>>()?
+            .iter()
+            .filter(|metadata| metadata.nlink() == 2)
+            .count();
+        assert!(linked > 0);
+    }
+    // `kvs backup` cannot take the store from its writer
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", "../cli-backup"])
+        .current_dir(&store_dir)
+        .assert()
+        .code(4)
+        .stderr(contains("locked"));
+    // overwrite everything and let compaction remove the backed up partitions
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    drop(store);
+
+    let restored_dir = temp_dir.path().join("restored");
+    kvs::backup::restore(&backup_dir, &restored_dir)?;
+    let mut store = KvStore::open(&restored_dir)?;
+    assert!(store.verify()?.is_ok());
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(&backup_dir)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+// `kvs backup` and `kvs restore` should round trip a store.
+#[test]
+fn cli_backup_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let mut store = KvStore::open(&store_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", "../backup"])
+        .current_dir(&store_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--path", "restored", "restore", "backup"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--path", "restored", "restore", "backup"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--path", "restored", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Ok(())
+}
+
+// A write batch is applied as a whole or not at all.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned())
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned());
+    store.write(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned()).remove("key1".to_owned());
+    assert!(matches!(store.write(batch), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Exported data should import into another store unchanged.
+#[test]
+fn export_import() -> Result<()> {
+    for format in &[Format::JsonLines, Format::Csv] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path().join("from"))?;
+        for key_id in 0..2500 {
+            store.set(format!("key{}", key_id), format!("value, \"{}\"\nline", key_id))?;
+        }
+        let mut exported = vec![];
+        assert_eq!(transfer::export(&store, &mut exported, *format)?, 2500);
+
+        let mut target = KvStore::open(temp_dir.path().join("to"))?;
+        target.set("key0".to_owned(), "kept".to_owned())?;
+        let mut batches = 0;
+        let stats = transfer::import(&mut target, &exported[..], *format, Conflict::SkipExisting, |_| batches += 1)?;
+        assert_eq!((stats.read, stats.written, stats.skipped), (2500, 2499, 1));
+        assert_eq!(batches, 3);
+        assert_eq!(target.get("key0".to_owned())?, Some("kept".to_owned()));
+        assert_eq!(target.get("key2499".to_owned())?, Some("value, \"2499\"\nline".to_owned()));
+
+        transfer::import(&mut target, &exported[..], *format, Conflict::Replace, |_| {})?;
+        assert_eq!(target.get("key0".to_owned())?, Some("value, \"0\"\nline".to_owned()));
+        assert_eq!(target.len(), 2500);
+    }
+    Ok(())
+}
+
+// `kvs export` output should be accepted by `kvs import`.
+#[test]
+fn cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().join("from"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--path", "from", "export", "--format", "csv"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "key,value\nkey1,value1\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--path", "from", "export", "dump.jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--path", "to", "import", "--replace", "--skip-existing", "dump.jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--path", "to", "import", "dump.jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("read 1 records, wrote 1, skipped 0"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--path", "to", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Ok(())
+}
+
+// Stats should account for live and stale data.
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.log_entries, 4);
+    assert_eq!(stats.partitions.len(), 1);
+    assert_eq!(stats.last_compaction, None);
+    assert_eq!(stats.live_bytes, "{\"Set\":[\"key2\",\"value3\"]}".len() as u64);
+    assert_eq!(stats.live_bytes + stats.stale_bytes + kvs::format::HEADER_LEN, stats.total_bytes);
+    assert!(stats.garbage_ratio > 0.5 && stats.garbage_ratio < 1.0);
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats", "--json"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(json["live_keys"], 1);
+    assert_eq!(json["log_entries"], 4);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("live keys:       1").and(contains("last compaction: never")));
+
+    // compaction drops the stale data and is recorded
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    let stats = store.stats()?;
+    assert!(stats.last_compaction.is_some());
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.stale_bytes, 0);
+    assert_eq!(stats.garbage_ratio, 0.0);
+    Ok(())
+}
+
+
+#[test]
+fn glob_patterns() {
+    assert!(glob_match("user:*", "user:1"));
+    assert!(glob_match("user:?", "user:1"));
+    assert!(!glob_match("user:?", "user:10"));
+    assert!(glob_match("key[0-4]", "key3"));
+    assert!(!glob_match("key[!0-4]", "key3"));
+    assert!(glob_match("a\\*b", "a*b"));
+    assert!(!glob_match("a\\*b", "axb"));
+}
+
+
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &["c", "a", "d", "b"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("d".to_owned())?;
+
+    assert_eq!(store.keys()?, vec!["a", "b", "c"]);
+    let pairs = store.scan("b".to_owned().."d".to_owned())?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs, vec![
+        ("b".to_owned(), "value-b".to_owned()),
+        ("c".to_owned(), "value-c".to_owned()),
+    ]);
+    assert_eq!(store.scan(..)?.take(1).count(), 1);
+    Ok(())
+}
+
+
+#[test]
+fn cli_keys_scan_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "one".to_owned())?;
+    store.set("user:2".to_owned(), "two\tvalues".to_owned())?;
+    store.set("item:1".to_owned(), "thing".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys", "user:*"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1\nuser:2\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys", "--prefix", "item", "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("\"item:1\"\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--from", "user:", "--limit", "5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1\tone\nuser:2\ttwo\\tvalues\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--to", "user:", "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"key\":\"item:1\",\"value\":\"thing\"}\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["count", "--prefix", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("2\n");
+    Ok(())
+}
+
+
+#[test]
+fn cli_shell_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["shell", "--batch"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key1 'two words'\n# a comment\nset key2 value2\nrm key2\nget key1\nscan\nget key2\n")
+        .assert()
+        .success()
+        .stdout("two words\nkey1\ttwo words\nKey not found\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["shell", "--batch"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("rm key2\nset key3 value3\n")
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+
+#[test]
+fn cli_json_output() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"ok\":true}\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"key\":\"key1\",\"value\":\"value1\"}\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"key\":\"key2\",\"value\":null}\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "keys"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("\"key1\"\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "count"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"count\":1}\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "rm", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(is_empty())
+        .stderr(contains("\"exit_code\":2"));
+    Ok(())
+}
+
+
+#[test]
+fn cli_exit_codes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stderr(contains("locked"));
+    drop(store);
+
+    // reading commands share the store with other readers, writing ones do not
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "--value-file", "missing.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(5);
+    std::fs::write(temp_dir.path().join("binary"), [0xff, 0xfe])?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "--value-file", "binary"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stderr(contains("not valid UTF-8"));
+    Ok(())
+}
+
+
+#[test]
+fn cli_set_value_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("value.txt"), "line 1\nline 2\n")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "--value-file", "value.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key2", "--value-file", "-"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("from\tstdin")
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key3", "value", "--value-file", "value.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("line 1\nline 2\n".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("from\tstdin".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+
+#[test]
+fn structured_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+
+    let partition = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("dblog".as_ref()))
+        .unwrap();
+    let file_id = u128::from_str_radix(partition.file_stem().unwrap().to_str().unwrap(), 16).unwrap();
+    let mut content = std::fs::read(&partition)?;
+    let valid_len = content.len() as u64;
+    content.extend_from_slice(b"{\"Set\":[\"key2\",");
+    std::fs::write(&partition, &content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { file_id: id, offset }) => {
+            assert_eq!((id, offset), (file_id, valid_len));
+        },
+        other => panic!("expected a corruption error, got {:?}", other),
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3);
+
+    std::fs::remove_file(&partition)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(err @ KvsError::MissingPartition { .. }) => assert!(err.is_corruption()),
+        other => panic!("expected a missing partition error, got {:?}", other),
+    }
+
+    std::fs::write(temp_dir.path().join("logparts"), b"{\"dirname\":")?;
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::CorruptMeta(_))));
+    Ok(())
+}
+
+
+#[test]
+fn format_migration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a version 1 store: no header in the partitions and no version in logparts
+    let file_id: u128 = 0x16b4c2d0a0e3f000;
+    std::fs::write(
+        temp_dir.path().join(format!("{:x}.dblog", file_id)),
+        b"{\"Set\":[\"key1\",\"value1\"]}{\"Set\":[\"key2\",\"value2\"]}{\"Remove\":\"key1\"}",
+    )?;
+    std::fs::write(
+        temp_dir.path().join("logparts"),
+        format!("{{\"dirname\":\"/elsewhere\",\"active\":{{\"entry_count\":3,\"file_id\":{}}},\"hist\":[]}}", file_id),
+    )?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormatVersion { found: 1, supported }) => assert_eq!(supported, kvs::FORMAT_VERSION),
+        other => panic!("expected an unsupported format version, got {:?}", other),
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("migrated from format version 1 to {}", kvs::FORMAT_VERSION)));
+    assert_eq!(format::migrate(temp_dir.path())?, kvs::FORMAT_VERSION);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.verify()?.is_ok());
+    store.close()?;
+    let content = std::fs::read(temp_dir.path().join(format!("{:x}.dblog", file_id)))?;
+    assert_eq!(&content[0..4], b"KVSP");
+
+    std::fs::write(temp_dir.path().join("logparts"), b"{\"format_version\":99}")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnsupportedFormatVersion { found: 99, .. })
+    ));
+    Ok(())
+}
+
+
+#[test]
+fn partition_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_partition_size(64).compaction(CompactionPolicy::Never);
+    let mut store = options.open(temp_dir.path())?;
+    for i in 0..6 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    store.close()?;
+
+    let mut names: Vec<String> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".dblog"))
+        .collect();
+    names.sort();
+    let expected: Vec<String> = (1..=names.len()).map(|id| format!("{:016x}.dblog", id)).collect();
+    assert!(names.len() > 2);
+    assert_eq!(names, expected);
+
+    // replay follows the generations, not the order partitions are listed in
+    let path = temp_dir.path().join("logparts");
+    let mut manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+    manifest["hist"].as_array_mut().unwrap().reverse();
+    std::fs::write(&path, serde_json::to_vec(&manifest)?)?;
+
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value5".to_owned()));
+    store.compact()?;
+    store.close()?;
+    assert!(temp_dir.path().join(format!("{:016x}.dblog", names.len() + 1)).exists());
+    Ok(())
+}
+
+
+#[test]
+fn watch_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_partition_size(64).compaction(CompactionPolicy::Never);
+    let mut store = options.open(temp_dir.path())?;
+    let watcher = store.watch("user:");
+    store.set("user:1".to_owned(), "one".to_owned())?;
+    store.set("item:1".to_owned(), "thing".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("user:2".to_owned(), "two".to_owned());
+    batch.remove("user:1".to_owned());
+    store.write(batch)?;
+    assert_eq!(store.last_seq(), 4);
+    assert_eq!(watcher.try_recv(), Some(Event::Set { seq: 1, key: "user:1".to_owned(), value: "one".to_owned() }));
+    assert_eq!(watcher.try_recv().map(|e| e.seq()), Some(3));
+    assert_eq!(watcher.try_recv(), Some(Event::Remove { seq: 4, key: "user:1".to_owned() }));
+    assert_eq!(watcher.try_recv(), None);
+    store.close()?;
+
+    let mut store = options.open(temp_dir.path())?;
+    store.set("user:3".to_owned(), "three".to_owned())?;
+    let seqs = store.changes_since(2)?.map(|e| e.map(|e| e.seq())).collect::<Result<Vec<_>>>()?;
+    assert_eq!(seqs, vec![3, 4, 5]);
+    assert_eq!(store.changes_since(5)?.count(), 0);
+
+    store.compact()?;
+    assert!(matches!(store.changes_since(2), Err(KvsError::ChangesCompacted { since: 2, compacted: 5 })));
+    store.set("user:4".to_owned(), "four".to_owned())?;
+    let events = store.changes_since(5)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(events, vec![Event::Set { seq: 6, key: "user:4".to_owned(), value: "four".to_owned() }]);
+    Ok(())
+}
+
+
+fn free_addr() -> std::net::SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+
+// Poll `addr` until `key` has `value`, the follower replicates asynchronously.
+fn wait_for(addr: std::net::SocketAddr, key: &str, value: Option<&str>) -> Result<()> {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    loop {
+        let found = KvsClient::connect(addr).and_then(|mut client| client.get(key.to_owned()));
+        match found {
+            Ok(ref found) if found.as_deref() == value => return Ok(()),
+            _ if std::time::Instant::now() > deadline => panic!("{} did not replicate: {:?}", key, found),
+            _ => std::thread::sleep(std::time::Duration::from_millis(50)),
+        }
+    }
+}
+
+
+#[test]
+fn replication() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_partition_size(64).compaction(CompactionPolicy::Never);
+
+    // the follower has a stale key and a position in a partition the primary compacted away
+    let mut primary = options.open(primary_dir.path())?;
+    for i in 0..20 {
+        primary.set(format!("key{}", i % 5), format!("value{}", i))?;
+    }
+    primary.compact()?;
+    let mut follower = KvStore::open(follower_dir.path())?;
+    follower.set("stale".to_owned(), "value".to_owned())?;
+    std::fs::write(follower_dir.path().join("replica"), r#"{"file_id":1,"offset":24}"#)?;
+
+    let (primary_addr, follower_addr) = (free_addr(), free_addr());
+    let server = KvsServer::new(primary);
+    std::thread::spawn(move || server.run(primary_addr));
+    let server = KvsServer::new(follower).follow(primary_addr)?;
+    assert!(server.is_follower());
+    std::thread::spawn(move || server.run(follower_addr));
+
+    wait_for(follower_addr, "key4", Some("value19"))?;
+    wait_for(follower_addr, "stale", None)?;
+    assert!(!follower_dir.path().join("replicasnapshot").exists());
+    for i in 0..5 {
+        wait_for(follower_addr, &format!("key{}", i), Some(&format!("value{}", i + 15)))?;
+    }
+
+    // new writes on the primary are shipped to the follower
+    let mut client = KvsClient::connect(primary_addr)?;
+    client.set("key0".to_owned(), "changed".to_owned())?;
+    client.remove("key1".to_owned())?;
+    client.set("key5".to_owned(), "new".to_owned())?;
+    wait_for(follower_addr, "key5", Some("new"))?;
+    wait_for(follower_addr, "key0", Some("changed"))?;
+    wait_for(follower_addr, "key1", None)?;
+    assert!(matches!(client.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
+
+    // the follower only serves reads
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key6", "value", "--addr", &follower_addr.to_string()])
+        .assert()
+        .failure()
+        .stderr(contains("read-only"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key5", "--addr", &follower_addr.to_string()])
+        .assert()
+        .success()
+        .stdout(eq("new").trim());
+    Ok(())
+}
+
+
+// Kills the server process when the test ends.
+struct Node(std::process::Child);
+
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+
+fn start_node(dir: &TempDir, member: Member, cluster: &str) -> Node {
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--addr", &member.addr.to_string(), "--node-id", &member.id.to_string()])
+        .current_dir(dir.path())
+        .stderr(std::process::Stdio::null());
+    if !cluster.is_empty() {
+        cmd.args(["--cluster", cluster]);
+    }
+    Node(cmd.spawn().unwrap())
+}
+
+
+// Poll the nodes until one of them is the leader of a term after `term`.
+fn wait_for_leader(members: &[Member], term: u64) -> Member {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while std::time::Instant::now() < deadline {
+        for member in members {
+            if let Ok(status) = KvsClient::connect(member.addr).and_then(|mut client| client.status()) {
+                if status.role == Role::Leader && status.term > term {
+                    return *member;
+                }
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    panic!("no leader was elected");
+}
+
+
+#[test]
+fn server_killed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStoreOptions::new().max_partition_size(200).open(temp_dir.path())?.close()?;
+    let start = |addr: std::net::SocketAddr| {
+        let node = Node(Command::cargo_bin("kvs-server").unwrap()
+            .args(["--addr", &addr.to_string()])
+            .current_dir(temp_dir.path())
+            .stderr(std::process::Stdio::null())
+            .spawn().unwrap());
+        wait_for(addr, "key", None).map(|_| node)
+    };
+
+    // the partitions rotated into and the result of compacting survive a kill
+    let addr = free_addr();
+    let mut node = start(addr)?;
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..100 {
+        client.set(format!("key{}", i % 20), format!("value{}", i))?;
+    }
+    node.0.kill()?;
+    node.0.wait()?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.stats()?.last_compaction.is_some());
+    assert_eq!(store.len(), 20);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 80..100 {
+        assert_eq!(store.get(format!("key{}", i % 20))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+
+    // a terminated server syncs the store before exiting
+    let addr = free_addr();
+    let mut node = start(addr)?;
+    KvsClient::connect(addr)?.set("key".to_owned(), "value".to_owned())?;
+    let status = Command::new("kill").args(["-TERM", &node.0.id().to_string()]).status()?;
+    assert!(status.success());
+    assert!(node.0.wait()?.success());
+    let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(temp_dir.path().join("logparts"))?)?;
+    assert!(manifest["active"]["entry_count"].as_u64().unwrap() > 0);
+    assert_eq!(KvStore::open(temp_dir.path())?.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+
+#[test]
+fn cluster_failover() -> Result<()> {
+    let dirs: Vec<_> = (0..3).map(|_| TempDir::new().expect("unable to create temporary working directory")).collect();
+    let members: Vec<_> = (1..=3).map(|id| Member { id, addr: free_addr() }).collect();
+    let cluster: Vec<_> = members.iter().map(|m| format!("{}={}", m.id, m.addr)).collect();
+    let mut nodes: Vec<_> = dirs.iter().zip(members.iter())
+        .map(|(dir, member)| Some(start_node(dir, *member, &cluster.join(","))))
+        .collect();
+
+    // writes sent to a follower are redirected to the leader
+    let leader = wait_for_leader(&members, 0);
+    let follower = members.iter().find(|m| m.id != leader.id).unwrap();
+    let mut client = KvsClient::connect(follower.addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(matches!(client.remove("key3".to_owned()), Err(KvsError::KeyNotFound)));
+    for member in members.iter() {
+        wait_for(member.addr, "key2", Some("value2"))?;
+    }
+
+    // the other nodes elect a new leader when the leader stops
+    let term = KvsClient::connect(leader.addr)?.status()?.term;
+    nodes[(leader.id - 1) as usize] = None;
+    let others: Vec<_> = members.iter().filter(|m| m.id != leader.id).cloned().collect();
+    let new_leader = wait_for_leader(&others, term);
+    let mut client = KvsClient::connect(others[0].addr)?;
+    client.remove("key1".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    for member in others.iter() {
+        wait_for(member.addr, "key3", Some("value3"))?;
+        wait_for(member.addr, "key1", None)?;
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["remove-node", &leader.id.to_string(), "--addr", &others[1].addr.to_string()])
+        .assert()
+        .success();
+    let status = KvsClient::connect(new_leader.addr)?.status()?;
+    assert_eq!(status.members, others);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["status", "--addr", &new_leader.addr.to_string()])
+        .assert()
+        .success()
+        .stdout(contains(format!("node {}: leader", new_leader.id)));
+    drop(nodes);
+    Ok(())
+}
+
+
+#[test]
+fn cluster_snapshots() -> Result<()> {
+    let first_dir = TempDir::new().expect("unable to create temporary working directory");
+    let second_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_partition_size(256);
+    let first = Member { id: 1, addr: free_addr() };
+    let second = Member { id: 2, addr: free_addr() };
+
+    // a cluster of one commits on its own
+    let server = KvsServer::new(options.open(first_dir.path())?).cluster(first.id, vec![first]);
+    std::thread::spawn(move || server.run(first.addr));
+    wait_for_leader(&[first], 0);
+    let mut client = KvsClient::connect(first.addr)?;
+    for _ in 0..2 {
+        client.set_many((0..2500).map(|i| (format!("bulk{}", i), i.to_string())).collect())?;
+    }
+    for i in 0..200 {
+        client.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+
+    // the log is snapshotted when the store compacts
+    let state: serde_json::Value = serde_json::from_slice(&std::fs::read(first_dir.path().join("raftstate"))?)?;
+    assert!(state["snapshot_index"].as_u64().unwrap() > 0);
+    let entries = std::fs::read_to_string(first_dir.path().join("raftlog"))?.lines().count();
+    assert!(entries < 200);
+
+    // a new node receives the store as a snapshot, replacing its own data
+    let mut store = KvStore::open(second_dir.path())?;
+    store.set("stale".to_owned(), "value".to_owned())?;
+    let server = KvsServer::new(store).cluster(second.id, Vec::new());
+    std::thread::spawn(move || server.run(second.addr));
+    client.add_node(second.id, second.addr)?;
+    for i in 1..10 {
+        wait_for(second.addr, &format!("key{}", i), Some(&format!("value{}", 190 + i)))?;
+    }
+    wait_for(second.addr, "key0", None)?;
+    wait_for(second.addr, "stale", None)?;
+    // a snapshot larger than a chunk is sent in several messages
+    wait_for(second.addr, "bulk0", Some("0"))?;
+    wait_for(second.addr, "bulk2499", Some("2499"))?;
+
+    client.set("key10".to_owned(), "value".to_owned())?;
+    wait_for(second.addr, "key10", Some("value"))?;
+    // the snapshot is received into its own directory, which goes once it is installed
+    assert!(!second_dir.path().join("raftsnapshot").exists());
+
+    // batches are replicated as one entry
+    client.set_many(vec![("batch1".to_owned(), "1".to_owned()), ("batch2".to_owned(), "2".to_owned())])?;
+    assert_eq!(client.remove_many(vec!["batch1".to_owned(), "missing".to_owned()])?, 1);
+    wait_for(second.addr, "batch2", Some("2"))?;
+    wait_for(second.addr, "batch1", None)?;
+    let status = KvsClient::connect(second.addr)?.status()?;
+    assert_eq!(status.members, vec![first, second]);
+    assert_eq!(status.leader, Some(first.id));
+    Ok(())
+}
+
+
+#[test]
+fn sharding() -> Result<()> {
+    let dirs: Vec<_> = (0..3).map(|_| TempDir::new().expect("unable to create temporary working directory")).collect();
+    let mut addrs = Vec::new();
+    for dir in dirs.iter() {
+        let (server, addr) = (KvsServer::new(KvStore::open(dir.path())?), free_addr());
+        std::thread::spawn(move || server.run(addr));
+        addrs.push(addr);
+    }
+    let count_keys = |addr| -> Result<usize> { Ok(KvsClient::connect(addr)?.scan(None, None, 1000)?.len()) };
+    for addr in addrs.iter() {
+        wait_for(*addr, "key", None)?;
+    }
+
+    let mut client = ShardedClient::connect(&addrs[..2])?;
+    for i in 0..300 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+    assert!(count_keys(addrs[0])? > 50);
+    assert!(count_keys(addrs[1])? > 50);
+    let keys = vec!["key1".to_owned(), "key0".to_owned(), "key299".to_owned(), "missing".to_owned()];
+    assert_eq!(client.get_many(keys)?, vec![Some("value1".to_owned()), None, Some("value299".to_owned()), None]);
+
+    // the new server takes over a part of the keys, the rest stays in place
+    let stats = client.add_server(addrs[2])?;
+    assert!(stats.scanned >= 299);
+    assert_eq!(count_keys(addrs[2])?, stats.moved);
+    assert!(stats.moved > 50 && stats.moved < 200);
+    for i in 1..300 {
+        let key = format!("key{}", i);
+        assert_eq!(KvsClient::connect(client.server_for(&key)?)?.get(key.clone())?, Some(format!("value{}", i)));
+    }
+    assert_eq!(client.rebalance()?.moved, 0);
+
+    let stats = client.remove_server(addrs[0])?;
+    assert_eq!(count_keys(addrs[0])?, 0);
+    assert_eq!(count_keys(addrs[1])? + count_keys(addrs[2])?, 299);
+    assert_eq!(stats.scanned, stats.moved);
+    let keys: Vec<_> = (0..300).map(|i| format!("key{}", i)).collect();
+    let values = client.get_many(keys)?;
+    assert_eq!(values.iter().filter(|value| value.is_some()).count(), 299);
+    assert_eq!(values[42], Some("value42".to_owned()));
+    Ok(())
+}
+
+
+// Read a RESP reply, rendered like redis-cli does.
+fn read_resp(reader: &mut impl std::io::BufRead) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let (kind, rest) = line.trim_end().split_at(1);
+    match kind {
+        "+" => rest.to_owned(),
+        "-" => format!("(error) {}", rest),
+        ":" => format!("(integer) {}", rest),
+        "$" if rest == "-1" => "(nil)".to_owned(),
+        "$" => {
+            let mut buf = vec![0; rest.parse::<usize>().unwrap() + 2];
+            reader.read_exact(&mut buf).unwrap();
+            String::from_utf8(buf[..buf.len() - 2].to_vec()).unwrap()
+        },
+        "*" => {
+            let items: Vec<_> = (0..rest.parse::<usize>().unwrap()).map(|_| read_resp(reader)).collect();
+            format!("[{}]", items.join(", "))
+        },
+        _ => panic!("unexpected reply {}", line),
+    }
+}
+
+
+fn resp(stream: &mut std::io::BufReader<std::net::TcpStream>, args: &[&str]) -> String {
+    use std::io::Write;
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    stream.get_mut().write_all(command.as_bytes()).unwrap();
+    read_resp(stream)
+}
+
+
+#[test]
+fn resp_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, resp_addr) = (free_addr(), free_addr());
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?).resp(resp_addr)?;
+    std::thread::spawn(move || server.run(addr));
+    wait_for(addr, "key", None)?;
+    let mut conn = std::io::BufReader::new(std::net::TcpStream::connect(resp_addr)?);
+
+    assert_eq!(resp(&mut conn, &["PING"]), "PONG");
+    assert_eq!(resp(&mut conn, &["set", "key1", "value1"]), "OK");
+    assert_eq!(resp(&mut conn, &["GET", "key1"]), "value1");
+    assert_eq!(resp(&mut conn, &["GET", "missing"]), "(nil)");
+    assert_eq!(resp(&mut conn, &["SET", "key1", "other", "NX"]), "(nil)");
+    assert_eq!(resp(&mut conn, &["SET", "key2", "value2", "XX"]), "(nil)");
+    assert_eq!(resp(&mut conn, &["SET", "key2", "value2", "NX"]), "OK");
+    assert_eq!(resp(&mut conn, &["MSET", "key3", "value3", "other", "x"]), "OK");
+    assert_eq!(resp(&mut conn, &["MGET", "key1", "missing", "key3"]), "[value1, (nil), value3]");
+    assert_eq!(resp(&mut conn, &["EXISTS", "key1", "key1", "missing"]), "(integer) 2");
+    assert_eq!(resp(&mut conn, &["KEYS", "key*"]), "[key1, key2, key3]");
+    assert_eq!(resp(&mut conn, &["DBSIZE"]), "(integer) 4");
+    assert_eq!(resp(&mut conn, &["DEL", "other", "other", "missing"]), "(integer) 1");
+    assert_eq!(resp(&mut conn, &["GET"]), "(error) ERR wrong number of arguments for 'get' command");
+    assert_eq!(resp(&mut conn, &["SET", "key1", "v", "EX", "0"]), "(error) ERR invalid expire time in 'set' command");
+    assert_eq!(resp(&mut conn, &["FLUSHALL"]), "(error) ERR unknown command 'FLUSHALL'");
+
+    // SCAN returns every key once over all pages
+    for i in 0..30 {
+        resp(&mut conn, &["SET", &format!("scan{}", i), "x"]);
+    }
+    let (mut cursor, mut found) = ("0".to_owned(), Vec::new());
+    loop {
+        let reply = resp(&mut conn, &["SCAN", &cursor, "MATCH", "scan*", "COUNT", "7"]);
+        let (next, keys) = reply[1..reply.len() - 2].split_once(", [").unwrap();
+        found.extend(keys.split(", ").filter(|key| !key.is_empty()).map(|key| key.to_owned()));
+        cursor = next.to_owned();
+        if cursor == "0" {
+            break;
+        }
+    }
+    found.sort();
+    let mut expected: Vec<_> = (0..30).map(|i| format!("scan{}", i)).collect();
+    expected.sort();
+    assert_eq!(found, expected);
+
+    // expired keys are gone for RESP and kvs clients alike
+    assert_eq!(resp(&mut conn, &["SET", "temp", "value", "PX", "100"]), "OK");
+    assert_eq!(resp(&mut conn, &["SET", "kept", "value", "EX", "1"]), "OK");
+    KvsClient::connect(addr)?.set("kept".to_owned(), "value".to_owned())?;
+    assert_eq!(resp(&mut conn, &["GET", "temp"]), "value");
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    assert_eq!(resp(&mut conn, &["GET", "temp"]), "(nil)");
+    assert_eq!(resp(&mut conn, &["GET", "kept"]), "value");
+    assert_eq!(KvsClient::connect(addr)?.get("temp".to_owned())?, None);
+
+    // pipelined commands are answered in order, inline commands work too
+    {
+        use std::io::Write;
+        conn.get_mut().write_all(b"*1\r\n$4\r\nPING\r\nGET key2\r\nQUIT\r\n")?;
+    }
+    assert_eq!(read_resp(&mut conn), "PONG");
+    assert_eq!(read_resp(&mut conn), "value2");
+    assert_eq!(read_resp(&mut conn), "OK");
+
+    // an argument that is not UTF-8 fails only its command
+    let mut conn = std::io::BufReader::new(std::net::TcpStream::connect(resp_addr)?);
+    {
+        use std::io::Write;
+        conn.get_mut().write_all(b"*2\r\n$3\r\nGET\r\n$2\r\n\xff\xfe\r\n")?;
+    }
+    assert_eq!(read_resp(&mut conn), "(error) ERR arguments must be valid UTF-8");
+    assert_eq!(resp(&mut conn, &["GET", "key2"]), "value2");
+    // a bulk string longer than allowed ends the connection
+    {
+        use std::io::Write;
+        conn.get_mut().write_all(b"*1\r\n$1000000000\r\n")?;
+    }
+    assert_eq!(read_resp(&mut conn), "(error) ERR Protocol error: invalid length");
+    Ok(())
+}
+
+
+// Send an HTTP request and return the status code and body.
+#[cfg(feature = "http")]
+fn http(addr: std::net::SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+    use std::io::{Read, Write};
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method, path, body.len(), body).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body).to_owned();
+    (status, body)
+}
+
+
+#[cfg(feature = "http")]
+#[test]
+fn http_interface() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, http_addr) = (free_addr(), free_addr());
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?).http(http_addr)?;
+    std::thread::spawn(move || server.run(addr));
+    wait_for(addr, "key", None)?;
+
+    assert_eq!(http(http_addr, "PUT", "/keys/user%2F1", r#"{"value":"one"}"#).0, 204);
+    assert_eq!(http(http_addr, "PUT", "/keys/user%2F2", r#"{"value":"two"}"#).0, 204);
+    assert_eq!(http(http_addr, "PUT", "/keys/item", r#"{"value":"x"}"#).0, 204);
+    assert_eq!(http(http_addr, "GET", "/keys/user%2F1", ""), (200, r#"{"key":"user/1","value":"one"}"#.to_owned()));
+    assert_eq!(http(http_addr, "GET", "/keys?prefix=user%2F", ""), (200, r#"{"keys":["user/1","user/2"]}"#.to_owned()));
+    assert_eq!(http(http_addr, "DELETE", "/keys/item", "").0, 204);
+
+    let (status, body) = http(http_addr, "GET", "/keys/item", "");
+    assert_eq!(status, 404);
+    let error: serde_json::Value = serde_json::from_str(&body)?;
+    assert_eq!(error["kind"], "key_not_found");
+    assert_eq!(error["error"], "Key not found");
+    assert_eq!(http(http_addr, "DELETE", "/keys/item", "").0, 404);
+    assert_eq!(http(http_addr, "PUT", "/keys/item", "not json").0, 400);
+    assert_eq!(http(http_addr, "POST", "/keys/item", "").0, 405);
+    assert_eq!(http(http_addr, "GET", "/nothing", "").0, 404);
+
+    assert_eq!(http(http_addr, "POST", "/compact", "").0, 204);
+    let (status, body) = http(http_addr, "GET", "/stats", "");
+    assert_eq!(status, 200);
+    let stats: serde_json::Value = serde_json::from_str(&body)?;
+    assert_eq!(stats["live_keys"], 2);
+    assert_eq!(KvsClient::connect(addr)?.get("user/2".to_owned())?, Some("two".to_owned()));
+    Ok(())
+}
+
+
+#[cfg(unix)]
+#[test]
+fn unix_socket() -> Result<()> {
+    use std::os::unix::{fs::PermissionsExt, net::UnixListener};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    // a socket file left behind by a server that is gone is replaced
+    drop(UnixListener::bind(&path)?);
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?).socket_mode(0o660);
+    let server_path = path.clone();
+    std::thread::spawn(move || server.run_unix(server_path));
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    let mut client = loop {
+        match KvsClient::connect_unix(&path) {
+            Ok(client) => break client,
+            Err(err) if std::time::Instant::now() > deadline => panic!("server did not start: {}", err),
+            Err(_) => std::thread::sleep(std::time::Duration::from_millis(50)),
+        }
+    };
+    assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, 0o660);
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get_many(vec!["key1".to_owned(), "missing".to_owned()])?, vec![Some("value1".to_owned()), None]);
+
+    Command::cargo_bin("kvs-client").unwrap()
+        .args(["get", "key1", "--socket"]).arg(&path)
+        .assert().success().stdout(eq("value1").trim());
+
+    // a socket with a server listening on it is not taken over
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let second = KvsServer::new(KvStore::open(other_dir.path())?);
+    assert!(second.run_unix(&path).is_err());
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // cluster nodes need a TCP address
+    let node_dir = TempDir::new().expect("unable to create temporary working directory");
+    let node = KvsServer::new(KvStore::open(node_dir.path())?).cluster(1, Vec::new());
+    assert!(matches!(node.run_unix(node_dir.path().join("kvs.sock")), Err(KvsError::InvalidOptions(_))));
+    Ok(())
+}
+
+
+// Run jobs after some that panic, all of them run on the remaining threads.
+fn check_pool<P: ThreadPool>(pool: P) {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for _ in 0..4 {
+        pool.spawn(|| panic!("a job panicked on purpose"));
+    }
+    for i in 0..100 {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(i).unwrap());
+    }
+    let mut done: Vec<_> = (0..100)
+        .map(|_| receiver.recv_timeout(std::time::Duration::from_secs(10)).expect("a job did not run"))
+        .collect();
+    done.sort();
+    assert_eq!(done, (0..100).collect::<Vec<_>>());
+}
+
+
+#[test]
+fn thread_pools() -> Result<()> {
+    check_pool(NaiveThreadPool::new(2)?);
+    check_pool(SharedQueueThreadPool::new(2)?);
+    check_pool(RayonThreadPool::new(2)?);
+    assert!(matches!(SharedQueueThreadPool::new(0), Err(KvsError::InvalidOptions(_))));
+
+    // the pool serves as many connections at once as it has threads, the
+    // others wait for one of them to close
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?).pool(SharedQueueThreadPool::new(2)?);
+    std::thread::spawn(move || server.run(addr));
+    wait_for(addr, "key", None)?;
+    let mut busy = (0..2).map(|_| KvsClient::connect(addr)).collect::<Result<Vec<_>>>()?;
+    for client in busy.iter_mut() {
+        client.set("key".to_owned(), "value".to_owned())?;
+    }
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let found = KvsClient::connect(addr).and_then(|mut client| client.get("key".to_owned()));
+        sender.send(found).unwrap();
+    });
+    assert!(receiver.recv_timeout(std::time::Duration::from_millis(300)).is_err());
+    busy.pop();
+    let found = receiver.recv_timeout(std::time::Duration::from_secs(10)).expect("the waiting connection was not served");
+    assert_eq!(found?, Some("value".to_owned()));
+    drop(busy);
+    let handles: Vec<_> = (0..8).map(|client| std::thread::spawn(move || -> Result<()> {
+        let mut conn = KvsClient::connect(addr)?;
+        for i in 0..20 {
+            conn.set(format!("client{}-key{}", client, i), i.to_string())?;
+        }
+        Ok(())
+    })).collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("client7-key19".to_owned())?, Some("19".to_owned()));
+    Ok(())
+}
+
+
+#[cfg(feature = "async")]
+#[test]
+fn async_api() -> Result<()> {
+    use kvs::{AsyncKvStore, AsyncKvsClient, AsyncKvsServer};
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = AsyncKvStore::open(temp_dir.path()).await?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+        let mut batch = WriteBatch::new();
+        batch.set("key2".to_owned(), "value2".to_owned()).remove("key1".to_owned());
+        store.write(batch).await?;
+        assert_eq!(store.keys().await?, vec!["key2".to_owned()]);
+        assert!(matches!(store.remove("key1".to_owned()).await, Err(KvsError::KeyNotFound)));
+
+        let addr = free_addr();
+        tokio::spawn(AsyncKvsServer::new(store.clone()).run(addr));
+        let mut client = loop {
+            match AsyncKvsClient::connect(addr).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        assert_eq!(client.get("key2".to_owned()).await?, Some("value2".to_owned()));
+        assert!(matches!(client.remove("missing".to_owned()).await, Err(KvsError::KeyNotFound)));
+
+        // many clients at the same time, each a task on the runtime
+        let tasks: Vec<_> = (0..10).map(|i| tokio::spawn(async move {
+            let mut client = AsyncKvsClient::connect(addr).await?;
+            client.set(format!("task{}", i), i.to_string()).await
+        })).collect();
+        for task in tasks {
+            task.await.unwrap()?;
+        }
+        assert_eq!(client.get_many(vec!["task3".to_owned(), "missing".to_owned()]).await?, vec![Some("3".to_owned()), None]);
+        assert_eq!(client.scan(Some("task".to_owned()), None, 2).await?.len(), 2);
+        client.set_many(vec![("batch1".to_owned(), "1".to_owned()), ("batch2".to_owned(), "2".to_owned())]).await?;
+        assert_eq!(client.remove_many(vec!["batch1".to_owned(), "batch2".to_owned(), "missing".to_owned()]).await?, 2);
+
+        // the blocking client speaks the same protocol
+        let value = tokio::task::spawn_blocking(move || KvsClient::connect(addr)?.get("task9".to_owned())).await.unwrap()?;
+        assert_eq!(value, Some("9".to_owned()));
+
+        // and the async client talks to the blocking server
+        let sync_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sync_addr = free_addr();
+        let server = KvsServer::new(KvStore::open(sync_dir.path())?);
+        std::thread::spawn(move || server.run(sync_addr));
+        tokio::task::spawn_blocking(move || wait_for(sync_addr, "key", None)).await.unwrap()?;
+        let mut client = AsyncKvsClient::connect(sync_addr).await?;
+        client.set("key".to_owned(), "value".to_owned()).await?;
+        assert_eq!(client.get("key".to_owned()).await?, Some("value".to_owned()));
+        Ok(())
+    })
+}
+
+
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    std::thread::spawn(move || server.run(addr));
+    wait_for(addr, "key", None)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    // batches
+    client.set_many(vec![("key1".to_owned(), "value1".to_owned()), ("key2".to_owned(), "value2".to_owned())])?;
+    assert_eq!(client.get_many(vec!["key1".to_owned(), "key2".to_owned()])?, vec![Some("value1".to_owned()), Some("value2".to_owned())]);
+    assert_eq!(client.remove_many(vec!["key1".to_owned(), "key1".to_owned(), "missing".to_owned()])?, 1);
+    assert_eq!(client.remove_many(vec!["missing".to_owned()])?, 0);
+
+    // responses come back in the order of the requests, errors per request
+    let mut pipeline = client.pipeline();
+    pipeline.set("key3".to_owned(), "value3".to_owned())
+        .get("key3".to_owned())
+        .remove("missing".to_owned())
+        .get_many(vec!["key2".to_owned(), "key1".to_owned()])
+        .remove_many(vec!["key2".to_owned(), "key3".to_owned()]);
+    let results = pipeline.execute()?;
+    assert_eq!(results.len(), 5);
+    assert!(matches!(results[0], Ok(Response::Ok)));
+    assert!(matches!(results[1], Ok(Response::Value(Some(ref value))) if value == "value3"));
+    assert!(matches!(results[2], Err(KvsError::KeyNotFound)));
+    assert!(matches!(results[3], Ok(Response::Values(ref values)) if values == &[Some("value2".to_owned()), None]));
+    assert!(matches!(results[4], Ok(Response::Count(2))));
+
+    // a pipeline larger than the socket buffers
+    let mut pipeline = client.pipeline();
+    for i in 0..20_000 {
+        pipeline.set(format!("key{}", i), "x".repeat(100));
+    }
+    assert!(pipeline.execute()?.iter().all(|result| matches!(result, Ok(Response::Ok))));
+    let mut pipeline = client.pipeline();
+    for i in 0..20_000 {
+        pipeline.get(format!("key{}", i));
+    }
+    assert!(pipeline.execute()?.iter().all(|result| matches!(result, Ok(Response::Value(Some(_))))));
+    // the connection is still in sync after the pipelines
+    assert_eq!(client.get("key19999".to_owned())?, Some("x".repeat(100)));
+
+    // a server that answers out of turn and stops reading fails the pipeline
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let stub = listener.local_addr()?;
+    std::thread::spawn(move || -> Result<()> {
+        let (stream, _) = listener.accept()?;
+        serde_json::to_writer(&stream, &Response::Ok)?;
+        std::thread::sleep(std::time::Duration::from_secs(60));
+        Ok(())
+    });
+    let mut client = KvsClient::connect(stub)?;
+    let mut pipeline = client.pipeline();
+    for i in 0..2_000 {
+        pipeline.set(format!("key{}", i), "x".repeat(10_000));
+    }
+    assert!(matches!(pipeline.execute(), Err(KvsError::Protocol(_))));
+
+    Command::cargo_bin("kvs-client").unwrap()
+        .args(["mset", "a", "1", "b", "2", "--addr", &addr.to_string()])
+        .assert().success();
+    Command::cargo_bin("kvs-client").unwrap()
+        .args(["mget", "a", "missing", "b", "--addr", &addr.to_string()])
+        .assert().success().stdout(eq("1\nKey not found\n2\n"));
+    Command::cargo_bin("kvs-client").unwrap()
+        .args(["mdel", "a", "b", "missing", "--addr", &addr.to_string()])
+        .assert().success().stdout(eq("2\n"));
+    Ok(())
+}